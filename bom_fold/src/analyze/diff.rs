@@ -0,0 +1,480 @@
+//! Structural comparison of two revisions of a hierarchy.
+
+use crate::{find_key, position_of_key, FoldedData, ItemSyncFormatRules, Node, OutputRules, Rules};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Single difference between the old and the new revision. `parent` is the id of the BOM the
+/// change is in, or `None` for top level items.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// The item was added to the parent.
+    Added { parent: Option<String>, id: String, quantity: f64 },
+
+    /// The item was removed from the parent.
+    Removed { parent: Option<String>, id: String, quantity: f64 },
+
+    /// The quantity of the item in the parent changed.
+    QuantityChanged { parent: Option<String>, id: String, old: f64, new: f64 },
+
+    /// A subassembly was removed from one parent and added to another.
+    Moved {
+        id: String,
+        old_parent: Option<String>,
+        new_parent: Option<String>,
+        old_quantity: f64,
+        new_quantity: f64,
+    },
+
+    /// The value of another attribute of the item in the parent changed.
+    AttributeChanged { parent: Option<String>, id: String, key: String, old: String, new: String },
+}
+
+impl Change {
+    /// Id of the item that changed.
+    pub fn id(&self) -> &str {
+        match self {
+            Change::Added { id, .. }
+            | Change::Removed { id, .. }
+            | Change::QuantityChanged { id, .. }
+            | Change::Moved { id, .. }
+            | Change::AttributeChanged { id, .. } => id,
+        }
+    }
+
+    /// Id of the BOM the change is listed under, which is the new parent for moves.
+    pub fn parent(&self) -> &Option<String> {
+        match self {
+            Change::Moved { new_parent, .. } => new_parent,
+            Change::Added { parent, .. }
+            | Change::Removed { parent, .. }
+            | Change::QuantityChanged { parent, .. }
+            | Change::AttributeChanged { parent, .. } => parent,
+        }
+    }
+}
+
+/// Changes from an old to a new revision of a hierarchy, as computed by [`diff`].
+#[derive(Debug, Default, PartialEq, Clone, Serialize)]
+pub struct BomDiff {
+    pub changes: Vec<Change>,
+}
+
+/// Compares the old and new revisions of a hierarchy, matching items by the ItemSync `id_key` of
+/// the rules.
+///
+/// Each BOM is compared by the set of items directly in it, so an item is only reported as added,
+/// removed or changed in its parent rather than for every path to it. Items listed several times
+/// in the same parent are compared by their total quantity and the attributes of the first line.
+/// Empty quantities count as 1, and other quantities must be numbers.
+/// The attributes compared are those with the same key in both revisions, other than the id,
+/// quantity and the level or parent key of the rules. The contents of a sub-BOM are only compared
+/// if the item is in both revisions, and sub-BOMs that appear in several places are compared where
+/// they first appear.
+pub fn diff(old: &FoldedData, new: &FoldedData, rules: &Rules) -> Result<BomDiff> {
+    let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+    let old_boms = Boms::new(old, item_sync_rules)?;
+    let new_boms = Boms::new(new, item_sync_rules)?;
+    let position_key = rules.position_key();
+    let compared_keys = old
+        .attribute_keys
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != old_boms.id && Some(*index) != old_boms.quantity)
+        .filter(|(_, key)| **key != position_key)
+        .filter_map(|(old_index, key)| {
+            let new_index = new.attribute_keys.iter().position(|new_key| new_key == key)?;
+            (new_index != new_boms.id && Some(new_index) != new_boms.quantity)
+                .then(|| (key.to_string(), old_index, new_index))
+        })
+        .collect::<Vec<_>>();
+
+    // The contents of added and removed subassemblies are implied by the subassembly itself.
+    let mut parents = Vec::new();
+    for parent in new_boms.parents.iter().chain(old_boms.parents.iter()) {
+        let in_both = match parent {
+            Some(id) => old_boms.ids.contains(id) && new_boms.ids.contains(id),
+            None => true,
+        };
+        if in_both && !parents.contains(&parent) {
+            parents.push(parent);
+        }
+    }
+
+    let mut changes = Vec::new();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for parent in parents.iter().copied() {
+        let old_lines = old_boms.lines.get(parent).map(Vec::as_slice).unwrap_or_default();
+        let new_lines = new_boms.lines.get(parent).map(Vec::as_slice).unwrap_or_default();
+        for new_line in new_lines {
+            let Some(old_line) = old_lines.iter().find(|line| line.id == new_line.id) else {
+                added.push((parent, new_line));
+                continue;
+            };
+            if old_line.quantity != new_line.quantity {
+                changes.push(Change::QuantityChanged {
+                    parent: parent.clone(),
+                    id: new_line.id.clone(),
+                    old: old_line.quantity,
+                    new: new_line.quantity,
+                });
+            }
+            for (key, old_index, new_index) in compared_keys.iter() {
+                let old_value = attribute(old_line.node, *old_index);
+                let new_value = attribute(new_line.node, *new_index);
+                if old_value != new_value {
+                    changes.push(Change::AttributeChanged {
+                        parent: parent.clone(),
+                        id: new_line.id.clone(),
+                        key: key.clone(),
+                        old: old_value,
+                        new: new_value,
+                    });
+                }
+            }
+        }
+        for old_line in old_lines {
+            if !new_lines.iter().any(|line| line.id == old_line.id) {
+                removed.push((parent, old_line));
+            }
+        }
+    }
+
+    // Pair up subassemblies that were removed from one parent and added to another.
+    for (old_parent, old_line) in removed {
+        let moved_to = added.iter().position(|(_, new_line)| {
+            new_line.id == old_line.id && (old_line.is_assembly() || new_line.is_assembly())
+        });
+        match moved_to {
+            Some(index) => {
+                let (new_parent, new_line) = added.remove(index);
+                changes.push(Change::Moved {
+                    id: old_line.id.clone(),
+                    old_parent: old_parent.clone(),
+                    new_parent: new_parent.clone(),
+                    old_quantity: old_line.quantity,
+                    new_quantity: new_line.quantity,
+                });
+            }
+            None => changes.push(Change::Removed {
+                parent: old_parent.clone(),
+                id: old_line.id.clone(),
+                quantity: old_line.quantity,
+            }),
+        }
+    }
+    changes.extend(added.into_iter().map(|(parent, line)| Change::Added {
+        parent: parent.clone(),
+        id: line.id.clone(),
+        quantity: line.quantity,
+    }));
+
+    // Group the changes by parent, in the order the parents appear.
+    changes.sort_by_key(|change| parents.iter().position(|parent| *parent == change.parent()));
+    Ok(BomDiff { changes })
+}
+
+/// Item directly in a BOM.
+struct Line<'a> {
+    id: String,
+    quantity: f64,
+    node: &'a Node<'a>,
+}
+
+impl Line<'_> {
+    fn is_assembly(&self) -> bool {
+        !self.node.children.is_empty()
+    }
+}
+
+/// The items directly in each BOM of a hierarchy, keyed by the id of the BOM.
+struct Boms<'a> {
+    id: usize,
+    quantity: Option<usize>,
+    quantity_key: &'a str,
+    /// Ids of the BOMs in depth first order, starting with `None` for the top level.
+    parents: Vec<Option<String>>,
+    lines: HashMap<Option<String>, Vec<Line<'a>>>,
+    /// Ids of all of the items.
+    ids: HashSet<String>,
+    /// Items with non-numeric quantities.
+    field_violations: Vec<FieldViolation>,
+}
+
+impl<'a> Boms<'a> {
+    /// Collects the BOMs of the hierarchy, where empty quantities count as 1. Fails if any other
+    /// quantity isn't a number.
+    fn new(folded_data: &'a FoldedData<'a>, rules: &'a ItemSyncFormatRules) -> Result<Self> {
+        let keys = folded_data.attribute_keys;
+        let mut boms = Boms {
+            id: find_key(keys, &rules.id_key, "id key")?,
+            quantity: rules.quantity_key.as_ref().and_then(|key| position_of_key(keys, key)),
+            quantity_key: rules.quantity_key.as_deref().unwrap_or_default(),
+            parents: Vec::new(),
+            lines: HashMap::new(),
+            ids: HashSet::new(),
+            field_violations: Vec::new(),
+        };
+        boms.add(None, &folded_data.top_level_nodes)?;
+        let field_violations = std::mem::take(&mut boms.field_violations);
+        if !field_violations.is_empty() {
+            return Err(Error::invalid_argument_with(
+                format!("Found {} non-numeric quantities", field_violations.len()),
+                Some(BadRequest { field_violations }),
+            ));
+        }
+        Ok(boms)
+    }
+
+    /// Adds the lines of the BOM, and then of its sub-BOMs if they weren't added before.
+    fn add(&mut self, parent: Option<String>, nodes: &'a [Node<'a>]) -> Result<()> {
+        if self.lines.contains_key(&parent) {
+            return Ok(());
+        }
+        let mut lines: Vec<Line> = Vec::new();
+        // Ids and children of the sub-BOMs, in order.
+        let mut sub_boms = Vec::new();
+        for node in nodes {
+            let id = node
+                .attributes
+                .get(self.id)
+                .ok_or_else(|| Error::invalid_argument("Node is missing id"))?
+                .to_string();
+            let quantity = match self.quantity.and_then(|index| node.attributes.get(index)) {
+                Some(value) if !value.is_empty() => super::number(value).unwrap_or_else(|| {
+                    self.field_violations.push(FieldViolation {
+                        field: Some(id.clone()),
+                        description: Some(format!(
+                            "{:?} = {:?} is not a number",
+                            self.quantity_key,
+                            value.to_string()
+                        )),
+                    });
+                    1.0
+                }),
+                _ => 1.0,
+            };
+            self.ids.insert(id.clone());
+            if !node.children.is_empty() {
+                sub_boms.push((id.clone(), &node.children));
+            }
+            match lines.iter_mut().find(|line| line.id == id) {
+                Some(line) => line.quantity += quantity,
+                None => lines.push(Line { id, quantity, node }),
+            }
+        }
+        self.parents.push(parent.clone());
+        self.lines.insert(parent, lines);
+        for (id, children) in sub_boms {
+            self.add(Some(id), children)?;
+        }
+        Ok(())
+    }
+}
+
+fn attribute(node: &Node, index: usize) -> String {
+    node.attributes.get(index).map(ToString::to_string).unwrap_or_default()
+}
+
+impl BomDiff {
+    /// Renders the changes as a report grouped by parent, e.g.
+    ///
+    /// ```text
+    /// A-100
+    ///   + N-400 (x8)
+    ///   - B-300 (x2)
+    ///   ~ 2001 quantity 4 -> 6
+    ///   ~ 2001 "Part Name" "Bracket" -> "Bracket, steel"
+    ///   > S-200 (x1) moved from B-100
+    /// ```
+    pub fn to_text(&self) -> String {
+        let mut output = String::new();
+        if self.changes.is_empty() {
+            output.push_str("No changes\n");
+            return output;
+        }
+        let mut current_parent = None;
+        for change in self.changes.iter() {
+            let parent = change.parent();
+            if current_parent != Some(parent) {
+                super::push_line(&mut output, parent.as_deref().unwrap_or("(top level)"));
+                current_parent = Some(parent);
+            }
+            let line = match change {
+                Change::Added { id, quantity, .. } => format!("  + {id} (x{quantity})"),
+                Change::Removed { id, quantity, .. } => format!("  - {id} (x{quantity})"),
+                Change::QuantityChanged { id, old, new, .. } => {
+                    format!("  ~ {id} quantity {old} -> {new}")
+                }
+                Change::AttributeChanged { id, key, old, new, .. } => {
+                    format!("  ~ {id} {key:?} {old:?} -> {new:?}")
+                }
+                Change::Moved { id, old_parent, new_quantity, .. } => format!(
+                    "  > {id} (x{new_quantity}) moved from {}",
+                    old_parent.as_deref().unwrap_or("(top level)")
+                ),
+            };
+            super::push_line(&mut output, line);
+        }
+        output
+    }
+
+    /// Serializes the changes as pretty printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| Error::internal(format!("Could not serialize diff: {e}")))
+    }
+
+    /// Renders the changes as a CSV change list with the columns "change", "parent", "id", "key",
+    /// "old" and "new". The key is "quantity" for quantity changes, and "parent" for moves.
+    pub fn to_csv(&self) -> Result<String> {
+        let parent_of = |parent: &Option<String>| parent.clone().unwrap_or_default();
+        let rows = self.changes.iter().map(|change| {
+            let (kind, key, old, new) = match change {
+                Change::Added { quantity, .. } => {
+                    ("added", "quantity", String::new(), quantity.to_string())
+                }
+                Change::Removed { quantity, .. } => {
+                    ("removed", "quantity", quantity.to_string(), String::new())
+                }
+                Change::QuantityChanged { old, new, .. } => {
+                    ("quantity_changed", "quantity", old.to_string(), new.to_string())
+                }
+                Change::AttributeChanged { key, old, new, .. } => {
+                    ("attribute_changed", key.as_str(), old.clone(), new.clone())
+                }
+                Change::Moved { old_parent, new_parent, .. } => {
+                    ("moved", "parent", parent_of(old_parent), parent_of(new_parent))
+                }
+            };
+            let parent = parent_of(change.parent());
+            [kind.to_string(), parent, change.id().to_string(), key.to_string(), old, new]
+        });
+        super::csv_string(["change", "parent", "id", "key", "old", "new"], rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Change};
+    use crate::fixtures::{flat_data, folded, rules, Items};
+    use crate::Value;
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    const OLD: &Items = &[
+        (0, "A-100", "Assembly", 1.0),
+        (1, "2001", "Bracket", 4.0),
+        (1, "S-200", "Subassembly", 1.0),
+        (2, "B-300", "Bolt", 2.0),
+        (1, "T-500", "Frame", 1.0),
+    ];
+
+    const NEW: &Items = &[
+        (0, "A-100", "Assembly", 1.0),
+        (1, "2001", "Bracket, steel", 6.0),
+        (1, "T-500", "Frame", 1.0),
+        (2, "S-200", "Subassembly", 1.0),
+        (3, "B-300", "Bolt", 2.0),
+        (2, "N-400", "Nut", 8.0),
+    ];
+
+    fn parent(id: &str) -> Option<String> {
+        Some(id.to_string())
+    }
+
+    #[test]
+    fn reports_changes_per_parent() {
+        let (old_data, new_data) = (flat_data(OLD), flat_data(NEW));
+        let result = diff(&folded(&old_data), &folded(&new_data), &rules()).unwrap();
+        assert_eq!(
+            result.changes,
+            [
+                Change::QuantityChanged {
+                    parent: parent("A-100"),
+                    id: "2001".into(),
+                    old: 4.0,
+                    new: 6.0
+                },
+                Change::AttributeChanged {
+                    parent: parent("A-100"),
+                    id: "2001".into(),
+                    key: "Part Name".into(),
+                    old: "Bracket".into(),
+                    new: "Bracket, steel".into(),
+                },
+                Change::Moved {
+                    id: "S-200".into(),
+                    old_parent: parent("A-100"),
+                    new_parent: parent("T-500"),
+                    old_quantity: 1.0,
+                    new_quantity: 1.0,
+                },
+                Change::Added { parent: parent("T-500"), id: "N-400".into(), quantity: 8.0 },
+            ]
+        );
+        assert_eq!(
+            result.to_text(),
+            "A-100\n  \
+             ~ 2001 quantity 4 -> 6\n  \
+             ~ 2001 \"Part Name\" \"Bracket\" -> \"Bracket, steel\"\n\
+             T-500\n  \
+             > S-200 (x1) moved from A-100\n  \
+             + N-400 (x8)\n"
+        );
+        assert_eq!(
+            result.to_csv().unwrap(),
+            "change,parent,id,key,old,new\n\
+             quantity_changed,A-100,2001,quantity,4,6\n\
+             attribute_changed,A-100,2001,Part Name,Bracket,\"Bracket, steel\"\n\
+             moved,T-500,S-200,parent,A-100,T-500\n\
+             added,T-500,N-400,quantity,,8\n"
+        );
+        let json = serde_json::from_str::<serde_json::Value>(&result.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json["changes"][3],
+            serde_json::json!({ "change": "added", "parent": "T-500", "id": "N-400", "quantity": 8.0 })
+        );
+    }
+
+    #[test]
+    fn reports_removed_parts_and_no_changes() {
+        let old_items: &Items = &[(0, "A-100", "Assembly", 1.0), (1, "2001", "Bracket", 4.0)];
+        let new_items: &Items = &[(0, "A-100", "Assembly", 1.0)];
+        let (old_data, new_data) = (flat_data(old_items), flat_data(new_items));
+        let (old, new) = (folded(&old_data), folded(&new_data));
+        assert_eq!(
+            diff(&old, &new, &rules()).unwrap().changes,
+            [Change::Removed { parent: parent("A-100"), id: "2001".into(), quantity: 4.0 }]
+        );
+        assert_eq!(diff(&old, &old, &rules()).unwrap().to_text(), "No changes\n");
+    }
+
+    #[test]
+    fn parses_and_checks_quantities() {
+        let old_data = flat_data(OLD);
+        let mut new_data = flat_data(OLD);
+        new_data.records[1][3] = Value::text(" 4 ");
+        new_data.records[2][3] = Value::text("");
+        let old = folded(&old_data);
+        assert_eq!(diff(&old, &folded(&new_data), &rules()).unwrap().changes, []);
+
+        new_data.records[1][3] = Value::text("4x");
+        let field_violations = match diff(&old, &folded(&new_data), &rules()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(field_violations.len(), 1);
+        assert_eq!(field_violations[0].field.as_deref(), Some("2001"));
+        assert_eq!(
+            field_violations[0].description.as_deref(),
+            Some("\"Quantity\" = \"4x\" is not a number")
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Analyses over the folded item hierarchy.
+
+mod diff;
+mod numeric_rollup;
+mod rollup;
+mod where_used;
+pub use diff::*;
+pub use numeric_rollup::*;
+pub use rollup::*;
+pub use where_used::*;
+
+use crate::Value;
+use error::{Error, Result};
+use std::fmt::{Display, Write};
+
+/// The value as a number, parsing text since rollup keys and quantities are only typed by the type
+/// mapping.
+pub(crate) fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Text(text) => text.trim().parse().ok().filter(|number: &f64| number.is_finite()),
+        value => value.as_f64(),
+    }
+}
+
+/// Renders the header and rows as CSV.
+fn csv_string<H, R>(header: H, rows: impl IntoIterator<Item = R>) -> Result<String>
+where
+    H: IntoIterator,
+    H::Item: AsRef<[u8]>,
+    R: IntoIterator,
+    R::Item: AsRef<[u8]>,
+{
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(header)?;
+    for row in rows {
+        writer.write_record(row)?;
+    }
+    let bytes =
+        writer.into_inner().map_err(|e| Error::internal(format!("Could not write CSV: {e}")))?;
+    String::from_utf8(bytes).map_err(|e| Error::internal(format!("Could not write CSV: {e}")))
+}
+
+/// Appends the line and a line break to a text report.
+fn push_line(output: &mut String, line: impl Display) {
+    // `write!` to a `String` can't fail.
+    let _ = writeln!(output, "{line}");
+}
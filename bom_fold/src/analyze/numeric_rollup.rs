@@ -0,0 +1,291 @@
+//! Numeric attributes, like cost or weight, summed up the hierarchy.
+
+use crate::{
+    find_key, position_of_key, FoldedData, Node, OutputRules, OwnedFoldedData, OwnedNode, Rules,
+    Value,
+};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use std::borrow::Cow;
+
+/// Adds the extended value of each of the `rollups` of the rules as an attribute of every item,
+/// replacing the attribute if the hierarchy already has one with the output key.
+///
+/// The values are computed bottom up: a part's extended value is its own value times its quantity,
+/// and an assembly's is the sum of the extended values of its children times its quantity, or its
+/// own value times its quantity if it isn't empty and `own_value_overrides` is set. Like the
+/// extended quantities, top level items are rolled up per one of them, whatever their quantity.
+/// Values and quantities are numbers or text that parses as one. Empty values count as 0 and empty
+/// or missing quantities as 1, and the extended values are rounded to 9 decimal places. Other
+/// values and quantities are rejected with an `InvalidArgument` error listing the items that have
+/// them.
+pub fn roll_up(folded_data: &FoldedData, rules: &Rules) -> Result<OwnedFoldedData> {
+    let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+    let keys = folded_data.attribute_keys;
+    let mut attribute_keys =
+        keys.iter().map(|key| Cow::from(key.to_string())).collect::<Vec<Cow<'static, str>>>();
+    let mut columns = Vec::new();
+    for rollup in rules.rollups.iter() {
+        let quantity = match rollup.quantity_key {
+            Some(ref key) => Some((find_key(keys, key, "rollup quantity key")?, key.as_str())),
+            None => item_sync_rules
+                .quantity_key
+                .as_ref()
+                .and_then(|key| Some((position_of_key(keys, key)?, key.as_str()))),
+        };
+        let output_key = rollup.output_key();
+        let output = match position_of_key(&attribute_keys, &output_key) {
+            Some(index) => index,
+            None => {
+                attribute_keys.push(Cow::from(output_key));
+                attribute_keys.len() - 1
+            }
+        };
+        columns.push(RollupColumn {
+            key: &rollup.key,
+            value: find_key(keys, &rollup.key, "rollup key")?,
+            quantity,
+            output,
+            own_value_overrides: rollup.own_value_overrides,
+        });
+    }
+    let mut rollup = Rollup {
+        id: find_key(keys, &item_sync_rules.id_key, "id key")?,
+        width: attribute_keys.len(),
+        columns,
+        field_violations: Vec::new(),
+    };
+    let top_level_nodes =
+        folded_data.top_level_nodes.iter().map(|node| rollup.roll_up(node, true)).collect();
+
+    let field_violations = rollup.field_violations;
+    if !field_violations.is_empty() {
+        return Err(Error::invalid_argument_with(
+            format!("Found {} non-numeric value(s) to roll up", field_violations.len()),
+            Some(BadRequest { field_violations }),
+        ));
+    }
+    Ok(OwnedFoldedData { attribute_keys, top_level_nodes })
+}
+
+/// Attribute positions of one of the rollups.
+struct RollupColumn<'r> {
+    key: &'r str,
+    value: usize,
+    /// Position and key of the quantities, if the items have any.
+    quantity: Option<(usize, &'r str)>,
+    /// Position of the extended values among the attributes of the output.
+    output: usize,
+    own_value_overrides: bool,
+}
+
+/// Walks the hierarchy, collecting the items with non-numeric values or quantities.
+struct Rollup<'r> {
+    id: usize,
+    /// Number of attributes of the output.
+    width: usize,
+    columns: Vec<RollupColumn<'r>>,
+    field_violations: Vec<FieldViolation>,
+}
+
+impl Rollup<'_> {
+    /// Copies the node with its children, adding its extended values.
+    fn roll_up(&mut self, node: &Node, top_level: bool) -> OwnedNode {
+        let children =
+            node.children.iter().map(|child| self.roll_up(child, false)).collect::<Vec<_>>();
+        let mut attributes = node.attributes.iter().map(Value::to_owned_value).collect::<Vec<_>>();
+        attributes.resize(self.width, Value::Empty);
+        for column in self.columns.iter() {
+            let own_value = match node.attributes.get(column.value) {
+                Some(value) if value.is_empty() => None,
+                Some(value) if super::number(value).is_some() => super::number(value),
+                Some(value) => {
+                    // Only values that are used are checked.
+                    if children.is_empty() || column.own_value_overrides {
+                        self.field_violations.push(FieldViolation {
+                            field: node.attributes.get(self.id).map(ToString::to_string),
+                            description: Some(format!(
+                                "{:?} = {:?} is not a number",
+                                column.key,
+                                value.to_string()
+                            )),
+                        });
+                    }
+                    None
+                }
+                None => None,
+            };
+            let value = match own_value {
+                Some(value) if children.is_empty() || column.own_value_overrides => value,
+                _ if children.is_empty() => 0.0,
+                _ => children
+                    .iter()
+                    .map(|child| match child.attributes[column.output] {
+                        Value::Number(value) => value,
+                        _ => 0.0,
+                    })
+                    .sum(),
+            };
+            let quantity = match column.quantity {
+                _ if top_level => 1.0,
+                Some((index, key)) => match node.attributes.get(index) {
+                    Some(value) if !value.is_empty() => super::number(value).unwrap_or_else(|| {
+                        let violation = FieldViolation {
+                            field: node.attributes.get(self.id).map(ToString::to_string),
+                            description: Some(format!(
+                                "{key:?} = {:?} is not a number",
+                                value.to_string()
+                            )),
+                        };
+                        // Rollups usually share the quantities, so report each one once.
+                        if !self.field_violations.contains(&violation) {
+                            self.field_violations.push(violation);
+                        }
+                        1.0
+                    }),
+                    _ => 1.0,
+                },
+                None => 1.0,
+            };
+            attributes[column.output] = Value::Number(round(value * quantity));
+        }
+        OwnedNode { attributes, children }
+    }
+}
+
+/// Rounds the value to 9 decimal places, which drops the noise of summing binary floating point
+/// numbers, like 0.1 + 0.2 = 0.30000000000000004, well below the precision of costs or weights.
+fn round(value: f64) -> f64 {
+    (value * 1e9).round() / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::roll_up;
+    use crate::{
+        fixtures::{add_column, flat_data, folded, Items},
+        FlatData, OwnedFoldedData, RollupRules, Rules, Value,
+    };
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    const ITEMS: &Items = &[
+        (0, "A-100", "Assembly", 1.0),
+        (1, "S-200", "Subassembly", 2.0),
+        (2, "B-300", "Bolt", 4.0),
+        (1, "N-400", "Nut", 3.0),
+    ];
+
+    fn input(bolt_cost: Value<'static>) -> FlatData<'static> {
+        let mut input = flat_data(ITEMS);
+        let cost = vec![Value::text(""), Value::Number(10.0), bolt_cost, Value::Number(0.25)];
+        add_column(&mut input, "Unit Cost", cost);
+        let weight =
+            vec![Value::Number(5.0), Value::text(""), Value::Number(0.5), Value::Number(0.5)];
+        add_column(&mut input, "Weight", weight);
+        input
+    }
+
+    fn rules(own_value_overrides: bool) -> Rules {
+        Rules {
+            rollups: vec![
+                RollupRules { own_value_overrides, ..RollupRules::new("Unit Cost") },
+                RollupRules {
+                    output_key: Some("Weight".to_string()),
+                    ..RollupRules::new("Weight")
+                },
+            ],
+            ..crate::fixtures::rules()
+        }
+    }
+
+    /// The id and rolled up values of each item, in depth first order.
+    fn rows(owned: &OwnedFoldedData) -> Vec<Vec<String>> {
+        let folded_data = owned.folded_data();
+        let columns = ["Part Number", "Weight", "Extended Unit Cost"]
+            .map(|key| owned.attribute_keys.iter().position(|k| k == key).unwrap());
+        let mut rows = Vec::new();
+        let mut stack = folded_data.top_level_nodes.iter().rev().collect::<Vec<_>>();
+        while let Some(node) = stack.pop() {
+            rows.push(columns.iter().map(|index| node.attributes[*index].to_string()).collect());
+            stack.extend(node.children.iter().rev());
+        }
+        rows
+    }
+
+    #[test]
+    fn rolls_up_extended_values() {
+        let input = input(Value::Number(1.5));
+        let owned = roll_up(&folded(&input), &rules(false)).unwrap();
+        assert_eq!(
+            owned.attribute_keys,
+            [
+                "level",
+                "Part Number",
+                "Part Name",
+                "Quantity",
+                "Unit Cost",
+                "Weight",
+                "Extended Unit Cost"
+            ]
+        );
+        assert_eq!(
+            rows(&owned),
+            [
+                ["A-100", "5.5", "12.75"],
+                ["S-200", "4", "12"],
+                ["B-300", "2", "6"],
+                ["N-400", "1.5", "0.75"]
+            ]
+        );
+
+        let owned = roll_up(&folded(&input), &rules(true)).unwrap();
+        assert_eq!(rows(&owned)[0], ["A-100", "5.5", "20.75"]);
+        assert_eq!(rows(&owned)[1], ["S-200", "4", "20"]);
+    }
+
+    #[test]
+    fn rejects_non_numeric_values() {
+        let input = input(Value::text("TBD"));
+        let field_violations = match roll_up(&folded(&input), &rules(false)) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(field_violations.len(), 1);
+        assert_eq!(field_violations[0].field.as_deref(), Some("B-300"));
+        assert_eq!(
+            field_violations[0].description.as_deref(),
+            Some("\"Unit Cost\" = \"TBD\" is not a number")
+        );
+    }
+
+    #[test]
+    fn parses_and_checks_quantities() {
+        let mut input = input(Value::Number(1.5));
+        input.records[2][3] = Value::text("4");
+        let owned = roll_up(&folded(&input), &rules(false)).unwrap();
+        assert_eq!(rows(&owned)[2], ["B-300", "2", "6"]);
+
+        input.records[2][3] = Value::text("four");
+        let field_violations = match roll_up(&folded(&input), &rules(false)) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(field_violations.len(), 1);
+        assert_eq!(field_violations[0].field.as_deref(), Some("B-300"));
+        assert_eq!(
+            field_violations[0].description.as_deref(),
+            Some("\"Quantity\" = \"four\" is not a number")
+        );
+    }
+
+    #[test]
+    fn rolls_up_top_level_items_per_one() {
+        let mut input = input(Value::text(" 1.5 "));
+        input.records[0][3] = Value::Number(2.0);
+        let owned = roll_up(&folded(&input), &rules(false)).unwrap();
+        assert_eq!(rows(&owned)[0], ["A-100", "5.5", "12.75"]);
+    }
+}
@@ -0,0 +1,308 @@
+//! Quantities multiplied down the hierarchy.
+
+use crate::{find_key, position_of_key, FoldedData, Node, OutputRules, Rules};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Item at one place in the hierarchy, with its quantity multiplied by those of its ancestors.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct ExtendedLine {
+    /// Ids of the ancestors of the item, starting with the top level item. Empty for top level
+    /// items.
+    pub path: Vec<String>,
+    pub id: String,
+    /// Name of the item, or empty if the rules have no name key.
+    pub name: String,
+    /// Quantity of the item in its parent.
+    pub quantity: f64,
+    /// Quantity of the item per one of the top level item it is under.
+    pub extended_quantity: f64,
+    /// Whether the item has no children, i.e. is a part rather than a subassembly.
+    pub is_leaf: bool,
+}
+
+impl ExtendedLine {
+    /// Id of the top level item the line is under, which is the item itself for top level items.
+    pub fn assembly(&self) -> &str {
+        self.path.first().unwrap_or(&self.id)
+    }
+}
+
+/// Lists every item of the hierarchy in depth first order with its extended quantity, the product
+/// of its quantity and the quantities of all of its ancestors other than the top level item.
+///
+/// Items are identified by the ItemSync `id_key` of the rules. Quantities are numbers or text that
+/// parses as one, and items with an empty or missing quantity count as 1, like in the BOM entries.
+/// Other quantities are rejected with an `InvalidArgument` error listing the items that have them.
+pub fn extended_quantities(folded_data: &FoldedData, rules: &Rules) -> Result<Vec<ExtendedLine>> {
+    let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+    let keys = folded_data.attribute_keys;
+    let mut rollup = Rollup {
+        id: find_key(keys, &item_sync_rules.id_key, "id key")?,
+        name: item_sync_rules.name_key.as_ref().and_then(|key| position_of_key(keys, key)),
+        quantity: item_sync_rules
+            .quantity_key
+            .as_ref()
+            .and_then(|key| Some((position_of_key(keys, key)?, key.as_str()))),
+        lines: Vec::new(),
+        field_violations: Vec::new(),
+    };
+    for node in folded_data.top_level_nodes.iter() {
+        rollup.add(node, &mut Vec::new(), 1.0);
+    }
+
+    let field_violations = rollup.field_violations;
+    if !field_violations.is_empty() {
+        return Err(Error::invalid_argument_with(
+            format!("Found {} non-numeric quantities", field_violations.len()),
+            Some(BadRequest { field_violations }),
+        ));
+    }
+    Ok(rollup.lines)
+}
+
+/// Walks the hierarchy, collecting the extended lines and the items with non-numeric quantities.
+struct Rollup<'r> {
+    id: usize,
+    name: Option<usize>,
+    /// Position and key of the quantities, if the items have any.
+    quantity: Option<(usize, &'r str)>,
+    lines: Vec<ExtendedLine>,
+    field_violations: Vec<FieldViolation>,
+}
+
+impl Rollup<'_> {
+    /// Adds the line of the node and then of its descendents. `multiplier` is the product of the
+    /// quantities of the ancestors in `path`.
+    fn add(&mut self, node: &Node, path: &mut Vec<String>, multiplier: f64) {
+        let attribute = |index: Option<usize>| {
+            index.and_then(|index| node.attributes.get(index)).map(ToString::to_string)
+        };
+        let id = attribute(Some(self.id)).unwrap_or_default();
+        let quantity = match self.quantity {
+            Some((index, key)) => match node.attributes.get(index) {
+                Some(value) if !value.is_empty() => super::number(value).unwrap_or_else(|| {
+                    self.field_violations.push(FieldViolation {
+                        field: Some(id.clone()),
+                        description: Some(format!(
+                            "{key:?} = {:?} is not a number",
+                            value.to_string()
+                        )),
+                    });
+                    1.0
+                }),
+                _ => 1.0,
+            },
+            None => 1.0,
+        };
+        // Top level items are rolled up per one of them, whatever their quantity.
+        let extended_quantity = if path.is_empty() { 1.0 } else { multiplier * quantity };
+        self.lines.push(ExtendedLine {
+            path: path.clone(),
+            id: id.clone(),
+            name: attribute(self.name).unwrap_or_default(),
+            quantity,
+            extended_quantity,
+            is_leaf: node.children.is_empty(),
+        });
+        path.push(id);
+        for child in node.children.iter() {
+            self.add(child, path, extended_quantity);
+        }
+        path.pop();
+    }
+}
+
+/// Total of one part under one top level item, as listed by [`parts_list`].
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct PartTotal {
+    /// Id of the top level item.
+    pub assembly: String,
+    pub id: String,
+    /// Name of the part where it first appears.
+    pub name: String,
+    /// Sum of the extended quantities of the part under the top level item.
+    pub total_quantity: f64,
+    /// Number of distinct parents, by their path from the top level item, the part is used in.
+    pub usages: usize,
+}
+
+/// Summarized parts list, with one row per part and top level item.
+#[derive(Debug, Default, PartialEq, Clone, Serialize)]
+pub struct PartsList {
+    pub parts: Vec<PartTotal>,
+}
+
+/// Sums the extended quantities of the parts, the items without children, under each top level
+/// item. The parts are listed per top level item in order of first appearance. Subassemblies are
+/// left out since their parts are already counted.
+pub fn parts_list(folded_data: &FoldedData, rules: &Rules) -> Result<PartsList> {
+    let mut parts: Vec<PartTotal> = Vec::new();
+    // Position of each part in `parts`, by top level item and id, and the places it was found in.
+    let mut indices: HashMap<(&str, &str), usize> = HashMap::new();
+    let mut places: Vec<HashSet<&[String]>> = Vec::new();
+    let lines = extended_quantities(folded_data, rules)?;
+    for line in lines.iter().filter(|line| line.is_leaf) {
+        let assembly = line.assembly();
+        match indices.get(&(assembly, line.id.as_str())) {
+            Some(&index) => {
+                parts[index].total_quantity += line.extended_quantity;
+                if places[index].insert(&line.path) {
+                    parts[index].usages += 1;
+                }
+            }
+            None => {
+                indices.insert((assembly, &line.id), parts.len());
+                parts.push(PartTotal {
+                    assembly: assembly.to_string(),
+                    id: line.id.clone(),
+                    name: line.name.clone(),
+                    total_quantity: line.extended_quantity,
+                    usages: 1,
+                });
+                places.push(HashSet::from([line.path.as_slice()]));
+            }
+        }
+    }
+    Ok(PartsList { parts })
+}
+
+impl PartsList {
+    /// Renders the parts list grouped by top level item, e.g.
+    ///
+    /// ```text
+    /// A-100
+    ///   2001  Bracket  x10 in 2 place(s)
+    ///   B-300  Bolt  x4 in 1 place(s)
+    /// ```
+    pub fn to_text(&self) -> String {
+        let mut output = String::new();
+        let mut current_assembly = None;
+        for part in self.parts.iter() {
+            if current_assembly != Some(&part.assembly) {
+                super::push_line(&mut output, &part.assembly);
+                current_assembly = Some(&part.assembly);
+            }
+            let name =
+                if part.name.is_empty() { String::new() } else { format!("  {}", part.name) };
+            super::push_line(
+                &mut output,
+                format!(
+                    "  {}{name}  x{} in {} place(s)",
+                    part.id, part.total_quantity, part.usages
+                ),
+            );
+        }
+        output
+    }
+
+    /// Serializes the parts list as pretty printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| Error::internal(format!("Could not serialize parts list: {e}")))
+    }
+
+    /// Renders the parts list as CSV with the columns "assembly", "id", "name", "total_quantity"
+    /// and "usages".
+    pub fn to_csv(&self) -> Result<String> {
+        let rows = self.parts.iter().map(|part| {
+            [
+                part.assembly.clone(),
+                part.id.clone(),
+                part.name.clone(),
+                part.total_quantity.to_string(),
+                part.usages.to_string(),
+            ]
+        });
+        super::csv_string(["assembly", "id", "name", "total_quantity", "usages"], rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extended_quantities, parts_list, PartTotal};
+    use crate::{
+        fixtures::{flat_data, folded, rules, Items},
+        Value,
+    };
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    const ITEMS: &Items = &[
+        (0, "A-100", "Assembly", 1.0),
+        (1, "S-200", "Subassembly", 2.0),
+        (2, "B-300", "Bolt", 3.0),
+        (2, "2001", "Bracket", 4.0),
+        (1, "B-300", "Bolt", 5.0),
+    ];
+
+    #[test]
+    fn multiplies_quantities_down_paths() {
+        let input = flat_data(ITEMS);
+        let lines = extended_quantities(&folded(&input), &rules()).unwrap();
+        assert_eq!(
+            lines
+                .iter()
+                .map(|line| (line.path.join(" > "), line.id.as_str(), line.extended_quantity))
+                .collect::<Vec<_>>(),
+            [
+                (String::new(), "A-100", 1.0),
+                ("A-100".to_string(), "S-200", 2.0),
+                ("A-100 > S-200".to_string(), "B-300", 6.0),
+                ("A-100 > S-200".to_string(), "2001", 8.0),
+                ("A-100".to_string(), "B-300", 5.0),
+            ]
+        );
+        assert_eq!(lines[2].assembly(), "A-100");
+        assert_eq!(lines[0].assembly(), "A-100");
+    }
+
+    #[test]
+    fn parses_and_checks_quantities() {
+        let mut input = flat_data(ITEMS);
+        input.records[2][3] = Value::text(" 3 ");
+        let lines = extended_quantities(&folded(&input), &rules()).unwrap();
+        assert_eq!((lines[2].quantity, lines[2].extended_quantity), (3.0, 6.0));
+
+        input.records[2][3] = Value::text("3x");
+        let field_violations = match extended_quantities(&folded(&input), &rules()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(field_violations.len(), 1);
+        assert_eq!(field_violations[0].field.as_deref(), Some("B-300"));
+        assert_eq!(
+            field_violations[0].description.as_deref(),
+            Some("\"Quantity\" = \"3x\" is not a number")
+        );
+    }
+
+    #[test]
+    fn sums_parts_per_assembly() {
+        let input = flat_data(ITEMS);
+        let parts_list = parts_list(&folded(&input), &rules()).unwrap();
+        let part = |id: &str, name: &str, total_quantity: f64, usages: usize| PartTotal {
+            assembly: "A-100".to_string(),
+            id: id.to_string(),
+            name: name.to_string(),
+            total_quantity,
+            usages,
+        };
+        assert_eq!(
+            parts_list.parts,
+            [part("B-300", "Bolt", 11.0, 2), part("2001", "Bracket", 8.0, 1)]
+        );
+        assert_eq!(
+            parts_list.to_csv().unwrap(),
+            "assembly,id,name,total_quantity,usages\nA-100,B-300,Bolt,11,2\nA-100,2001,Bracket,8,1\n"
+        );
+        assert_eq!(
+            parts_list.to_text(),
+            "A-100\n  B-300  Bolt  x11 in 2 place(s)\n  2001  Bracket  x8 in 1 place(s)\n"
+        );
+    }
+}
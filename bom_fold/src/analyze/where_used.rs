@@ -0,0 +1,206 @@
+//! Reverse index from items to the assemblies they are used in.
+
+use crate::{extended_quantities, FoldedData, Rules};
+use error::{Error, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Single place an item is used in.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Usage {
+    /// Ids of the ancestors of the item, from the top level item down to the immediate parent.
+    pub path: Vec<String>,
+    /// Quantity of the item in the immediate parent.
+    pub quantity: f64,
+    /// Quantity of the item per one of the top level item, see
+    /// [`extended_quantities`](crate::extended_quantities).
+    pub extended_quantity: f64,
+}
+
+impl Usage {
+    /// Id of the assembly the item is directly in.
+    pub fn parent(&self) -> &str {
+        self.path.last().map(String::as_str).unwrap_or_default()
+    }
+}
+
+/// Everywhere one item is used, as returned by [`WhereUsedIndex::where_used`].
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct WhereUsed {
+    pub id: String,
+    /// Ids of the assemblies the item is directly in, in order of first appearance.
+    pub parents: Vec<String>,
+    /// Every path to the item, in depth first order.
+    pub usages: Vec<Usage>,
+}
+
+/// Index of the places each item of a hierarchy is used in, keyed by the ItemSync `id_key` of the
+/// rules.
+#[derive(Debug, Default, Clone)]
+pub struct WhereUsedIndex {
+    usages: HashMap<String, Vec<Usage>>,
+}
+
+impl WhereUsedIndex {
+    /// Builds the index over every path of the hierarchy. Top level items are indexed without
+    /// usages, unless they are also used in another item.
+    pub fn new(folded_data: &FoldedData, rules: &Rules) -> Result<Self> {
+        let mut usages: HashMap<String, Vec<Usage>> = HashMap::new();
+        for line in extended_quantities(folded_data, rules)? {
+            let item_usages = usages.entry(line.id).or_default();
+            if !line.path.is_empty() {
+                item_usages.push(Usage {
+                    path: line.path,
+                    quantity: line.quantity,
+                    extended_quantity: line.extended_quantity,
+                });
+            }
+        }
+        Ok(WhereUsedIndex { usages })
+    }
+
+    /// The places the item is used in, or `None` if the hierarchy doesn't contain it.
+    pub fn usages(&self, id: &str) -> Option<&[Usage]> {
+        self.usages.get(id).map(Vec::as_slice)
+    }
+
+    /// Ids of the assemblies the item is directly in, in order of first appearance.
+    pub fn parents(&self, id: &str) -> Vec<&str> {
+        let mut parents = Vec::new();
+        for usage in self.usages(id).unwrap_or_default() {
+            if !parents.contains(&usage.parent()) {
+                parents.push(usage.parent());
+            }
+        }
+        parents
+    }
+
+    /// Collects the parents and usages of the item, or returns a `NotFound` error if the
+    /// hierarchy doesn't contain it.
+    pub fn where_used(&self, id: &str) -> Result<WhereUsed> {
+        let usages = self
+            .usages(id)
+            .ok_or_else(|| Error::not_found(format!("Couldn't find item {id:?} in the input")))?;
+        Ok(WhereUsed {
+            id: id.to_string(),
+            parents: self.parents(id).into_iter().map(str::to_string).collect(),
+            usages: usages.to_vec(),
+        })
+    }
+}
+
+impl WhereUsed {
+    /// Renders the parents followed by one line per path, e.g.
+    ///
+    /// ```text
+    /// B-300 is used in 2 place(s) of 1 top level item(s)
+    /// Parents: S-200, A-100
+    ///   A-100 > S-200 > B-300 (x3, extended x6)
+    ///   A-100 > B-300 (x5, extended x5)
+    /// ```
+    pub fn to_text(&self) -> String {
+        let mut output = String::new();
+        if self.usages.is_empty() {
+            super::push_line(&mut output, format!("{} is only used as a top level item", self.id));
+            return output;
+        }
+        let mut top_level_items = Vec::new();
+        for usage in self.usages.iter() {
+            if !top_level_items.contains(&&usage.path[0]) {
+                top_level_items.push(&usage.path[0]);
+            }
+        }
+        super::push_line(
+            &mut output,
+            format!(
+                "{} is used in {} place(s) of {} top level item(s)",
+                self.id,
+                self.usages.len(),
+                top_level_items.len()
+            ),
+        );
+        super::push_line(&mut output, format!("Parents: {}", self.parents.join(", ")));
+        for usage in self.usages.iter() {
+            super::push_line(
+                &mut output,
+                format!(
+                    "  {} > {} (x{}, extended x{})",
+                    usage.path.join(" > "),
+                    self.id,
+                    usage.quantity,
+                    usage.extended_quantity
+                ),
+            );
+        }
+        output
+    }
+
+    /// Serializes the parents and usages as pretty printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| Error::internal(format!("Could not serialize where-used report: {e}")))
+    }
+
+    /// Renders the usages as CSV with the columns "assembly", "parent", "path", "quantity" and
+    /// "extended_quantity", where the path is the ids from the top level item to the parent joined
+    /// by " > ".
+    pub fn to_csv(&self) -> Result<String> {
+        let rows = self.usages.iter().map(|usage| {
+            [
+                usage.path[0].clone(),
+                usage.parent().to_string(),
+                usage.path.join(" > "),
+                usage.quantity.to_string(),
+                usage.extended_quantity.to_string(),
+            ]
+        });
+        super::csv_string(["assembly", "parent", "path", "quantity", "extended_quantity"], rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WhereUsedIndex;
+    use crate::fixtures::{flat_data, folded, rules, Items};
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    /// A-100 with S-200 (containing B-300) and B-300, and T-500 with another S-200.
+    const ITEMS: &Items = &[
+        (0, "A-100", "Assembly", 1.0),
+        (1, "S-200", "Subassembly", 2.0),
+        (2, "B-300", "Bolt", 3.0),
+        (1, "B-300", "Bolt", 5.0),
+        (0, "T-500", "Frame", 1.0),
+        (1, "S-200", "Subassembly", 4.0),
+        (2, "B-300", "Bolt", 3.0),
+    ];
+
+    #[test]
+    fn indexes_every_path() {
+        let input = flat_data(ITEMS);
+        let index = WhereUsedIndex::new(&folded(&input), &rules()).unwrap();
+        assert_eq!(index.parents("B-300"), ["S-200", "A-100"]);
+        assert_eq!(index.parents("S-200"), ["A-100", "T-500"]);
+        assert_eq!(index.usages("A-100"), Some(&[][..]));
+        assert_eq!(index.usages("Z-999"), None);
+
+        let where_used = index.where_used("B-300").unwrap();
+        assert_eq!(
+            where_used.to_text(),
+            "B-300 is used in 3 place(s) of 2 top level item(s)\n\
+             Parents: S-200, A-100\n  \
+             A-100 > S-200 > B-300 (x3, extended x6)\n  \
+             A-100 > B-300 (x5, extended x5)\n  \
+             T-500 > S-200 > B-300 (x3, extended x12)\n"
+        );
+        assert_eq!(
+            where_used.to_csv().unwrap(),
+            "assembly,parent,path,quantity,extended_quantity\n\
+             A-100,S-200,A-100 > S-200,3,6\n\
+             A-100,A-100,A-100,5,5\n\
+             T-500,S-200,T-500 > S-200,3,12\n"
+        );
+        assert!(matches!(index.where_used("Z-999"), Err(Error::NotFound(_))));
+    }
+}
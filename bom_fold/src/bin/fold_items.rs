@@ -1,82 +1,700 @@
 use bom_fold::{
-    transform, ChildIdentificationPolicy, FlatData, ItemSyncFormat, ItemSyncFormatRules,
-    OutputRules, Rules, ValueType,
+    consolidate, diff, parts_list, render_tree, roll_up, transform, unfold,
+    ChildIdentificationPolicy, ConsolidationRules, FlatData, FoldedData, HeaderRow, ItemSyncFormat,
+    ItemSyncFormatRules, LevelStyle, LevelValidation, OutputRules, OwnedFoldedData, RollupRules,
+    Rules, SheetSelection, SubtreeSelection, TreeOptions, Value, ValueType, WhereUsedIndex,
 };
-use clap::Parser;
-use std::{fs::File, io::Read, path::Path};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use error::{Error, Result};
+use std::{io, path::Path, process};
 
-/// Parses a level ordered BOM flat file and writes ItemSync compatible output.
+/// Parses BOM flat files and converts them into ItemSync compatible output.
 ///
-/// Currently only CSV input is supported.
+/// Both CSV and XLSX input are supported. For XLSX input, the first worksheet is used unless
+/// another worksheet is selected.
 #[derive(Parser, Debug)]
 #[clap(version = "0.1.0", author = "Idan <idan@lightsource.ai>")]
 struct Opts {
-    /// Input file path
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Folds the input and writes the ItemSync output.
+    Fold {
+        #[clap(flatten)]
+        input: InputOpts,
+
+        /// Output directory where files will be written. If not set, the folded hierarchy is
+        /// printed to stdout as a tree instead.
+        #[clap(long)]
+        output: Option<String>,
+
+        /// Format of the files written to the output directory.
+        #[clap(long, value_enum, default_value_t = OutputFormat::Csv)]
+        output_format: OutputFormat,
+    },
+    /// Checks that the input can be folded into ItemSync output without writing anything.
+    Validate {
+        #[clap(flatten)]
+        input: InputOpts,
+    },
+    /// Prints the headers of the input along with the types of their values and the row count.
+    Inspect {
+        #[clap(flatten)]
+        input: InputOpts,
+    },
+    /// Prints the folded hierarchy as an indented tree.
+    Tree {
+        #[clap(flatten)]
+        input: InputOpts,
+
+        #[clap(flatten)]
+        tree: TreeOpts,
+    },
+    /// Writes the input, as read according to the rules, to a CSV or XLSX file. Headers are
+    /// renamed to the keys they match, preamble and footer rows are dropped and indentation is
+    /// extracted into a level column.
+    Convert {
+        #[clap(flatten)]
+        input: InputOpts,
+
+        /// Path of the file to write, ending in .csv or .xlsx.
+        #[clap(long)]
+        output: String,
+    },
+    /// Writes the folded hierarchy back to a CSV or XLSX file with one row per item and a level
+    /// column, e.g. to turn a parent referenced BOM or folded JSON into an indented BOM.
+    Unfold {
+        #[clap(flatten)]
+        input: InputOpts,
+
+        /// Path of the file to write, ending in .csv or .xlsx.
+        #[clap(long)]
+        output: String,
+
+        /// How the level of each item is written.
+        #[clap(long, value_enum, default_value_t = OutputLevelStyle::Depth)]
+        level_style: OutputLevelStyle,
+
+        /// Key of the level column. If the input already has a column with this key, its values
+        /// are replaced. Defaults to the level key of the rules, or "level" for parent
+        /// referenced input.
+        #[clap(long)]
+        level_key: Option<String>,
+    },
+    /// Compares the input, as the old revision, with a new revision of the BOM and prints the
+    /// added, removed and moved items, and the changed quantities and attributes per parent.
+    Diff {
+        #[clap(flatten)]
+        input: InputOpts,
+
+        /// Path of the new revision, which is read with the same options as the input.
+        #[clap(long)]
+        new: String,
+
+        /// Format of the printed changes.
+        #[clap(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+    },
+    /// Prints every assembly that uses an item, with the path to it from each top level item and
+    /// its extended quantities.
+    WhereUsed {
+        #[clap(flatten)]
+        input: InputOpts,
+
+        /// Id of the item to look up, as found under the id key of the rules.
+        #[clap(long)]
+        part: String,
+
+        /// Format of the printed usages.
+        #[clap(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+    },
+    /// Prints the total quantity of each part per top level item, multiplying the quantities down
+    /// every path, along with the number of places the part is used in.
+    PartsList {
+        #[clap(flatten)]
+        input: InputOpts,
+
+        /// Format of the printed parts list.
+        #[clap(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+    },
+}
+
+/// Options for reading the input, shared by all of the commands.
+#[derive(Args, Clone, Debug)]
+struct InputOpts {
+    /// Input file path. Commands that only need the folded hierarchy, like `tree`, also accept
+    /// the nested JSON written by `fold --output-format json`.
     #[clap(long)]
     input: String,
 
-    /// Output directory where files will be written.
+    /// Path to a JSON, TOML or YAML rules file describing the input columns and hierarchy. If not
+    /// set, the input must contain the keys "Part Number", "Part Name", "Quantity" and "level", or
+    /// common aliases of them.
     #[clap(long)]
-    output: Option<String>,
+    rules: Option<String>,
+
+    /// Name of the worksheet to read from XLSX input.
+    #[clap(long, conflicts_with_all = ["sheet_index", "all_sheets"])]
+    sheet_name: Option<String>,
+
+    /// Zero-based position of the worksheet to read from XLSX input.
+    #[clap(long, conflicts_with = "all_sheets")]
+    sheet_index: Option<usize>,
+
+    /// Read every worksheet of XLSX input, folding the items of each worksheet on their own.
+    #[clap(long)]
+    all_sheets: bool,
+
+    /// Zero-based position of the header row, for input with rows above the headers that can't be
+    /// skipped automatically. This is the line of a CSV file or the row of a worksheet, counting
+    /// blank ones.
+    #[clap(long)]
+    header_row: Option<usize>,
+
+    /// Keep trailing rows that are missing a part number or level, like a totals row, instead of
+    /// dropping them.
+    #[clap(long)]
+    keep_footer_rows: bool,
+
+    /// Reject input where the first row isn't a top level item or a row skips levels, instead of
+    /// attaching such rows to the closest preceding shallower item with a warning.
+    #[clap(long)]
+    strict_levels: bool,
+
+    /// Read the input as previously written ItemSync output instead of a flat BOM: either a
+    /// directory containing "boms.csv" and "bom_entries.csv", or an XLSX workbook with "BOMs" and
+    /// "BOM Entries" worksheets. Sub-BOMs are expanded into a multi-level hierarchy whose
+    /// attributes are named after the id, name and quantity keys of the rules.
+    #[clap(long)]
+    item_sync: bool,
+
+    /// Merge items listed several times under the same parent, summing their quantities. Implied
+    /// when the rules file has a `consolidation` section, which also sets the attributes whose
+    /// values are concatenated, like reference designators.
+    #[clap(long)]
+    consolidate: bool,
+
+    /// Comma separated keys of numeric attributes, like "Unit Cost" or "Weight", to roll up the
+    /// hierarchy. Each adds an "Extended <key>" attribute with the value times the quantity of
+    /// every item, where the value of an assembly is the sum of its children. Added to the
+    /// `rollups` of the rules file.
+    #[clap(long, value_delimiter = ',')]
+    rollup: Vec<String>,
 }
 
-fn main() {
-    let opts = Opts::parse();
-    let input_path = Path::new(&opts.input);
-    let file_extension = input_path.extension();
+/// Options for printing the folded hierarchy as a tree.
+#[derive(Args, Debug, Default)]
+struct TreeOpts {
+    /// Comma separated keys of the attributes to print for each item. Defaults to the id and name
+    /// keys of the output rules, followed by the rolled up attributes.
+    #[clap(long, value_delimiter = ',')]
+    columns: Vec<String>,
+
+    /// Only print items up to this depth, where the top level items have depth 0.
+    #[clap(long)]
+    max_depth: Option<usize>,
+
+    /// Only print the subtree(s) of the item(s) with this part number.
+    #[clap(long)]
+    root: Option<String>,
+}
+
+impl TreeOpts {
+    /// Fills in the tree options from the flags, with defaults taken from the output rules.
+    fn tree_options(&self, rules: &Rules) -> TreeOptions {
+        let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+        // Like the ItemSync output, the default columns don't require a name.
+        let (columns, optional_columns) = if self.columns.is_empty() {
+            let optional_columns = item_sync_rules
+                .name_key
+                .clone()
+                .into_iter()
+                .chain(rules.rollups.iter().map(RollupRules::output_key))
+                .collect();
+            (vec![item_sync_rules.id_key.clone()], optional_columns)
+        } else {
+            (self.columns.clone(), Vec::new())
+        };
+        TreeOptions {
+            columns,
+            optional_columns,
+            quantity_key: item_sync_rules.quantity_key.clone(),
+            max_depth: self.max_depth,
+            subtree: self.root.as_ref().map(|root| SubtreeSelection {
+                key: item_sync_rules.id_key.clone(),
+                value: root.clone(),
+            }),
+        }
+    }
+}
+
+/// Format of the reports printed by `diff`, `parts-list` and `where-used`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ReportFormat {
+    /// Human readable report, grouped by parent for `diff` and by top level item for
+    /// `parts-list`, or one line per path for `where-used`.
+    Text,
+    /// Pretty printed JSON, e.g. `{"changes": [..]}` with one object per change.
+    Json,
+    /// One row per change, part or path, e.g. with the columns change, parent, id, key, old and new.
+    Csv,
+}
+
+/// Level column style of the `unfold` output.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputLevelStyle {
+    /// The depth of each item, where top level items have level 1.
+    Depth,
+    /// Dotted outline numbers like "1.2.10".
+    Outline,
+}
+
+impl From<OutputLevelStyle> for LevelStyle {
+    fn from(style: OutputLevelStyle) -> Self {
+        match style {
+            OutputLevelStyle::Depth => LevelStyle::Depth,
+            OutputLevelStyle::Outline => LevelStyle::Outline,
+        }
+    }
+}
+
+/// File format(s) of the output.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputFormat {
+    /// "boms.csv" and "bom_entries.csv".
+    Csv,
+    /// "item_sync.xlsx", with a "BOMs" and a "BOM Entries" worksheet.
+    Xlsx,
+    /// "folded.json", with the folded hierarchy as nested JSON instead of the ItemSync output.
+    Json,
+}
+
+impl InputOpts {
+    /// Worksheet(s) selected by the sheet flags, if any.
+    fn sheet_selection(&self) -> Option<SheetSelection> {
+        if let Some(ref name) = self.sheet_name {
+            Some(SheetSelection::Name(name.clone()))
+        } else if let Some(index) = self.sheet_index {
+            Some(SheetSelection::Index(index))
+        } else if self.all_sheets {
+            Some(SheetSelection::All)
+        } else {
+            None
+        }
+    }
+
+    /// Loads the rules file if set, or the default rules otherwise, and applies the overrides from
+    /// the other flags.
+    fn rules(&self) -> Result<Rules> {
+        let mut rules = match self.rules {
+            Some(ref path) => Rules::from_path(Path::new(path))?,
+            None => default_rules(),
+        };
+        if let Some(sheet_selection) = self.sheet_selection() {
+            rules.sheet_selection = sheet_selection;
+        }
+        if let Some(index) = self.header_row {
+            rules.header_row = HeaderRow::Index(index);
+        }
+        if self.keep_footer_rows {
+            rules.drop_footer_rows = false;
+        }
+        if self.strict_levels {
+            rules.level_validation = LevelValidation::Strict;
+        }
+        if self.consolidate && rules.consolidation.is_none() {
+            rules.consolidation = Some(ConsolidationRules::default());
+        }
+        for key in self.rollup.iter() {
+            if !rules.rollups.iter().any(|rollup| rollup.key == *key) {
+                rules.rollups.push(RollupRules::new(key));
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Reads the contents of the input file, which must be a flat BOM or folded JSON.
+    fn read(&self) -> Result<Vec<u8>> {
+        if self.item_sync {
+            return Err(Error::invalid_argument(
+                "ItemSync input can only be used by commands that need the folded hierarchy, like \
+                 fold, tree and unfold",
+            ));
+        }
+        self.read_file()
+    }
+
+    /// Reads the contents of the input file, whatever its kind.
+    fn read_file(&self) -> Result<Vec<u8>> {
+        std::fs::read(&self.input).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                Error::not_found(format!("Couldn't find input file {:?}", self.input))
+            }
+            _ => Error::from(e),
+        })
+    }
+
+    /// Folds the input according to the rules and calls `f` with the folded hierarchy, processed by
+    /// [`with_processed_data`]. JSON input is read as an already folded hierarchy.
+    fn with_folded_data<T>(
+        &self,
+        rules: &Rules,
+        f: impl FnOnce(&FoldedData) -> Result<T>,
+    ) -> Result<T> {
+        self.with_read_data(rules, |folded_data| with_processed_data(folded_data, rules, f))
+    }
 
-    let mut file = File::open(input_path).expect("Couldn't open `input` file");
-    let mut file_contents = Vec::new();
-    file.read_to_end(&mut file_contents).expect("Failed to read input file");
+    /// Like [`InputOpts::with_folded_data`], but without consolidating or rolling up anything.
+    fn with_read_data<T>(
+        &self,
+        rules: &Rules,
+        f: impl FnOnce(&FoldedData) -> Result<T>,
+    ) -> Result<T> {
+        if self.item_sync {
+            let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+            let input = Path::new(&self.input);
+            let owned = if input.is_dir() {
+                OwnedFoldedData::from_item_sync_csv(
+                    &std::fs::read(input.join("boms.csv"))?,
+                    &std::fs::read(input.join("bom_entries.csv"))?,
+                    item_sync_rules,
+                )?
+            } else {
+                OwnedFoldedData::from_item_sync_xlsx(&self.read_file()?, item_sync_rules)?
+            };
+            return f(&owned.folded_data());
+        }
+        if Path::new(&self.input).extension().and_then(|e| e.to_str()) == Some("json") {
+            let owned = OwnedFoldedData::from_json(&String::from_utf8_lossy(&self.read()?))?;
+            return f(&owned.folded_data());
+        }
+        let file_contents = self.read()?;
+        let flat_data = self.parse(&file_contents, rules)?;
+        f(&transform(&flat_data, rules)?)
+    }
+
+    /// Parses the file contents according to the input file extension.
+    fn parse<'a>(&self, file_contents: &'a [u8], rules: &Rules) -> Result<FlatData<'a>> {
+        match Path::new(&self.input).extension().and_then(|e| e.to_str()) {
+            Some("csv") => FlatData::from_csv(file_contents, rules),
+            Some("xlsx") => FlatData::from_xlsx(file_contents, rules),
+            _ => Err(Error::invalid_argument(
+                "Unrecognized file type. Please ensure your file has a .csv or .xlsx extension",
+            )),
+        }
+    }
+}
 
-    let fixed_rules = Rules {
+/// Rules for input with the keys "Part Number", "Part Name", "Quantity" and "level", or common
+/// aliases of them.
+fn default_rules() -> Rules {
+    Rules {
         type_mapping: Some([("Quantity".to_string(), ValueType::Number)].into_iter().collect()),
+        number_formats: None,
+        header_aliases: Some(
+            [
+                ("Part Number", &["PN", "P/N", "Part No", "Item Number"][..]),
+                ("Part Name", &["Name", "Description"]),
+                ("Quantity", &["Qty"]),
+                ("level", &["Lvl", "BOM Level"]),
+            ]
+            .into_iter()
+            .map(|(key, aliases)| {
+                (key.to_string(), aliases.iter().map(ToString::to_string).collect())
+            })
+            .collect(),
+        ),
+        header_row: HeaderRow::Detect,
+        drop_footer_rows: true,
+        sheet_selection: SheetSelection::First,
         child_identification_policy: ChildIdentificationPolicy::OrderedLevelKey(
             "level".to_string(),
         ),
+        level_validation: LevelValidation::Lenient,
+        consolidation: None,
+        rollups: Vec::new(),
         output_rules: OutputRules::ItemSync(ItemSyncFormatRules {
             id_key: "Part Number".to_string(),
             name_key: Some("Part Name".to_string()),
             quantity_key: Some("Quantity".to_string()),
         }),
-    };
+    }
+}
 
-    let flat_data = match file_extension.and_then(|e| e.to_str()) {
-        Some("csv") => FlatData::from_csv(&file_contents, &fixed_rules).unwrap(),
-        Some("xlsx") => unimplemented!("Excel is not yet supported"),
-        _ => {
-            panic!("Unrecognized file type. Please ensure your file has a .csv or .xlsx extension")
+fn main() {
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+    let opts = Opts::try_parse().unwrap_or_else(|e| {
+        if !e.use_stderr() {
+            // --help and --version.
+            e.exit()
         }
+        // Usage errors are reported like any other invalid argument.
+        let _ = e.print();
+        process::exit(Error::INVALID_ARGUMENT_CODE)
+    });
+    let result = match opts.command {
+        Command::Fold { input, output, output_format } => {
+            fold(&input, output.as_deref(), output_format)
+        }
+        Command::Validate { input } => validate(&input),
+        Command::Inspect { input } => inspect(&input),
+        Command::Tree { input, tree: tree_opts } => tree(&input, &tree_opts),
+        Command::Convert { input, output } => convert(&input, &output),
+        Command::Unfold { input, output, level_style, level_key } => {
+            unfold_to(&input, &output, level_style.into(), level_key.as_deref())
+        }
+        Command::Diff { input, new, format } => diff_revisions(&input, new, format),
+        Command::PartsList { input, format } => print_parts_list(&input, format),
+        Command::WhereUsed { input, part, format } => where_used(&input, &part, format),
     };
-    let folded_data = transform(&flat_data, &fixed_rules).unwrap();
-    let formatted_data = match &fixed_rules.output_rules {
-        OutputRules::ItemSync(item_sync_rules) => {
-            ItemSyncFormat::format_item_sync(&folded_data, item_sync_rules).unwrap()
+    if let Err(error) = result {
+        report(&error);
+        process::exit(error.exit_code());
+    }
+}
+
+/// Prints the error code and message to stderr, followed by any violations in the error details.
+fn report(error: &Error) {
+    eprintln!("error[{}]: {}", error.client_code(), error.message());
+    match error {
+        Error::InvalidArgument(payload) | Error::OutOfRange(payload) => {
+            for violation in payload.payload.iter().flat_map(|p| p.field_violations.iter()) {
+                eprintln!(
+                    "  {}: {}",
+                    violation.field.as_deref().unwrap_or_default(),
+                    violation.description.as_deref().unwrap_or_default()
+                );
+            }
         }
+        Error::FailedPrecondition(payload) => {
+            for violation in payload.payload.iter().flat_map(|p| p.violations.iter()) {
+                eprintln!(
+                    "  {} {}: {}",
+                    violation.r#type.as_deref().unwrap_or_default(),
+                    violation.subject.as_deref().unwrap_or_default(),
+                    violation.description.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Calls `f` with the folded hierarchy, consolidated and with rolled up values if the rules ask
+/// for it.
+fn with_processed_data<T>(
+    folded_data: &FoldedData,
+    rules: &Rules,
+    f: impl FnOnce(&FoldedData) -> Result<T>,
+) -> Result<T> {
+    let with_rollups = |folded_data: &FoldedData| match rules.rollups.is_empty() {
+        true => f(folded_data),
+        false => f(&roll_up(folded_data, rules)?.folded_data()),
     };
+    match rules.consolidation {
+        Some(_) => with_rollups(&consolidate(folded_data, rules)?.folded_data()),
+        None => with_rollups(folded_data),
+    }
+}
+
+/// Folds the input and writes the ItemSync output to the output directory, or prints it.
+fn fold(input: &InputOpts, output_dir: Option<&str>, output_format: OutputFormat) -> Result<()> {
+    let rules = input.rules()?;
+    input.with_folded_data(&rules, |folded_data| {
+        match output_dir {
+            Some(output_dir) => write_output(folded_data, &rules, output_dir, output_format)?,
+            None => {
+                print!("{}", render_tree(folded_data, &TreeOpts::default().tree_options(&rules))?)
+            }
+        }
+        Ok(())
+    })
+}
 
-    if let Some(output_dir) = opts.output {
-        write_output(&formatted_data, &output_dir);
-    } else {
-        println!("{formatted_data:?}")
+/// Folds and formats the input, and prints a summary if that succeeds.
+fn validate(input: &InputOpts) -> Result<()> {
+    let rules = input.rules()?;
+    let file_contents = input.read()?;
+    let flat_data = input.parse(&file_contents, &rules)?;
+    let folded_data = transform(&flat_data, &rules)?;
+    with_processed_data(&folded_data, &rules, |folded_data| {
+        let formatted_data = format(folded_data, &rules)?;
+        println!(
+            "OK: {} row(s), {} top level item(s), {} BOM(s) with {} entries",
+            flat_data.records.len(),
+            folded_data.top_level_nodes.len(),
+            formatted_data.boms.len(),
+            formatted_data.bom_entries.len()
+        );
+        Ok(())
+    })
+}
+
+/// Prints each header with the types of its values, followed by the row count.
+fn inspect(input: &InputOpts) -> Result<()> {
+    let rules = input.rules()?;
+    let file_contents = input.read()?;
+    let flat_data = input.parse(&file_contents, &rules)?;
+    for (index, key) in flat_data.keys.iter().enumerate() {
+        // Counts of the non-empty values of each type, in the order they are printed.
+        let mut counts = [("text", 0), ("number", 0), ("integer", 0), ("boolean", 0), ("date", 0)];
+        let mut empty = 0;
+        for value in flat_data.records.iter().filter_map(|record| record.get(index)) {
+            let type_index = match value {
+                _ if value.is_empty() => {
+                    empty += 1;
+                    continue;
+                }
+                Value::Text(_) | Value::Empty => 0,
+                Value::Number(_) => 1,
+                Value::Integer(_) => 2,
+                Value::Boolean(_) => 3,
+                Value::Date(_) => 4,
+            };
+            counts[type_index].1 += 1;
+        }
+        let present = counts.iter().filter(|(_, count)| *count > 0).collect::<Vec<_>>();
+        let value_type = match present[..] {
+            [] => "Empty".to_string(),
+            [(name, _)] => name[..1].to_uppercase() + &name[1..],
+            _ => "Mixed".to_string(),
+        };
+        // Text and number counts are always printed, the others only if there are any.
+        let counts = counts
+            .iter()
+            .enumerate()
+            .filter(|(index, (_, count))| *index < 2 || *count > 0)
+            .map(|(_, (name, count))| format!("{count} {name}, "))
+            .collect::<String>();
+        println!("{key:?}: {value_type} ({counts}{empty} empty value(s))");
     }
+    println!("{} row(s)", flat_data.records.len());
+    Ok(())
 }
 
-/// Writes the formatted_data to CSV.
-fn write_output(formatted_data: &ItemSyncFormat, output_dir: &str) {
-    let output_dir = Path::new(output_dir);
-    std::fs::create_dir_all(output_dir).unwrap();
+/// Prints the folded hierarchy as a tree.
+fn tree(input: &InputOpts, tree_opts: &TreeOpts) -> Result<()> {
+    let rules = input.rules()?;
+    input.with_folded_data(&rules, |folded_data| {
+        print!("{}", render_tree(folded_data, &tree_opts.tree_options(&rules))?);
+        Ok(())
+    })
+}
+
+/// Writes the input as read according to the rules to a CSV or XLSX file.
+fn convert(input: &InputOpts, output: &str) -> Result<()> {
+    let rules = input.rules()?;
+    let file_contents = input.read()?;
+    let flat_data = input.parse(&file_contents, &rules)?;
+    write_flat_data(&flat_data, output)
+}
+
+/// Unfolds the folded hierarchy and writes it to a CSV or XLSX file.
+fn unfold_to(
+    input: &InputOpts,
+    output: &str,
+    level_style: LevelStyle,
+    level_key: Option<&str>,
+) -> Result<()> {
+    let rules = input.rules()?;
+    let level_key = level_key.unwrap_or(match rules.child_identification_policy {
+        ChildIdentificationPolicy::OrderedLevelKey(ref key)
+        | ChildIdentificationPolicy::OutlineLevelKey(ref key) => key,
+        ChildIdentificationPolicy::IndentedKey(ref indentation) => &indentation.level_key,
+        ChildIdentificationPolicy::Absolute(_) => "level",
+    });
+    input.with_folded_data(&rules, |folded_data| {
+        write_flat_data(&unfold(folded_data, level_key, level_style), output)
+    })
+}
+
+/// Compares the input with the new revision and prints the changes in the format.
+fn diff_revisions(input: &InputOpts, new: String, format: ReportFormat) -> Result<()> {
+    let rules = input.rules()?;
+    let new_input = InputOpts { input: new, ..input.clone() };
+    let changes = input.with_folded_data(&rules, |old_data| {
+        new_input.with_folded_data(&rules, |new_data| diff(old_data, new_data, &rules))
+    })?;
+    match format {
+        ReportFormat::Text => print!("{}", changes.to_text()),
+        ReportFormat::Json => println!("{}", changes.to_json()?),
+        ReportFormat::Csv => print!("{}", changes.to_csv()?),
+    }
+    Ok(())
+}
+
+/// Prints the summarized parts list of the input in the format.
+fn print_parts_list(input: &InputOpts, format: ReportFormat) -> Result<()> {
+    let rules = input.rules()?;
+    let parts_list =
+        input.with_folded_data(&rules, |folded_data| parts_list(folded_data, &rules))?;
+    match format {
+        ReportFormat::Text => print!("{}", parts_list.to_text()),
+        ReportFormat::Json => println!("{}", parts_list.to_json()?),
+        ReportFormat::Csv => print!("{}", parts_list.to_csv()?),
+    }
+    Ok(())
+}
 
-    let mut boms_writer = csv::Writer::from_path(output_dir.join("boms.csv")).unwrap();
-    for bom in formatted_data.boms.iter() {
-        boms_writer.serialize(bom).unwrap()
+/// Prints the usages of the part in the input in the format.
+fn where_used(input: &InputOpts, part: &str, format: ReportFormat) -> Result<()> {
+    let rules = input.rules()?;
+    let where_used = input.with_folded_data(&rules, |folded_data| {
+        WhereUsedIndex::new(folded_data, &rules)?.where_used(part)
+    })?;
+    match format {
+        ReportFormat::Text => print!("{}", where_used.to_text()),
+        ReportFormat::Json => println!("{}", where_used.to_json()?),
+        ReportFormat::Csv => print!("{}", where_used.to_csv()?),
     }
-    boms_writer.flush().unwrap();
+    Ok(())
+}
+
+/// Writes the flat data to a CSV or XLSX file, depending on the output extension.
+fn write_flat_data(flat_data: &FlatData, output: &str) -> Result<()> {
+    let output = Path::new(output);
+    match output.extension().and_then(|e| e.to_str()) {
+        Some("csv") => flat_data.write_csv(output),
+        Some("xlsx") => flat_data.write_xlsx(output, "BOM"),
+        _ => Err(Error::invalid_argument(
+            "Unrecognized output file type. Please use a .csv or .xlsx extension",
+        )),
+    }
+}
 
-    let mut bom_entries_writer =
-        csv::Writer::from_path(output_dir.join("bom_entries.csv")).unwrap();
-    for entry in formatted_data.bom_entries.iter() {
-        bom_entries_writer.serialize(entry).unwrap();
+/// Formats the folded data according to the output rules.
+fn format<'a>(folded_data: &'a FoldedData, rules: &Rules) -> Result<ItemSyncFormat<'a>> {
+    match &rules.output_rules {
+        OutputRules::ItemSync(item_sync_rules) => {
+            let entry_keys = rules.rollups.iter().map(RollupRules::output_key).collect::<Vec<_>>();
+            ItemSyncFormat::format_item_sync_with_attributes(
+                folded_data,
+                item_sync_rules,
+                &entry_keys,
+            )
+        }
+    }
+}
+
+/// Formats the folded data and writes it to the output directory in the output format.
+fn write_output(
+    folded_data: &FoldedData,
+    rules: &Rules,
+    output_dir: &str,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let output_dir = Path::new(output_dir);
+    std::fs::create_dir_all(output_dir)?;
+    match output_format {
+        OutputFormat::Csv => format(folded_data, rules)?.write_csv(output_dir),
+        OutputFormat::Xlsx => {
+            format(folded_data, rules)?.write_xlsx(&output_dir.join("item_sync.xlsx"))
+        }
+        OutputFormat::Json => folded_data.write_json(&output_dir.join("folded.json")),
     }
-    bom_entries_writer.flush().unwrap();
 }
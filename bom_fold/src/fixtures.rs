@@ -0,0 +1,74 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{
+    transform, ChildIdentificationPolicy, FlatData, FoldedData, HeaderRow, ItemSyncFormatRules,
+    LevelValidation, OutputRules, Rules, SheetSelection, Value, ValueType,
+};
+use std::borrow::Cow;
+
+/// Items as (level, id, name, quantity) in depth first order, with 0 as the top level.
+pub type Items = [(i64, &'static str, &'static str, f64)];
+
+/// Item sync rules for the "Part Number", "Part Name" and "Quantity" keys.
+pub fn item_sync_rules() -> ItemSyncFormatRules {
+    ItemSyncFormatRules {
+        id_key: "Part Number".to_string(),
+        name_key: Some("Part Name".to_string()),
+        quantity_key: Some("Quantity".to_string()),
+    }
+}
+
+/// Rules that fold by the "level" key and output the [`item_sync_rules`].
+pub fn rules() -> Rules {
+    Rules {
+        type_mapping: Some([("Quantity".to_string(), ValueType::Number)].into_iter().collect()),
+        number_formats: None,
+        header_aliases: None,
+        header_row: HeaderRow::Detect,
+        drop_footer_rows: true,
+        sheet_selection: SheetSelection::First,
+        child_identification_policy: ChildIdentificationPolicy::OrderedLevelKey(
+            "level".to_string(),
+        ),
+        level_validation: LevelValidation::Strict,
+        consolidation: None,
+        rollups: Vec::new(),
+        output_rules: OutputRules::ItemSync(item_sync_rules()),
+    }
+}
+
+/// Flat data with the "level", "Part Number", "Part Name" and "Quantity" of the items.
+pub fn flat_data(items: &Items) -> FlatData<'static> {
+    FlatData {
+        keys: ["level", "Part Number", "Part Name", "Quantity"]
+            .into_iter()
+            .map(Cow::from)
+            .collect(),
+        records: items
+            .iter()
+            .map(|(level, id, name, quantity)| {
+                vec![
+                    Value::Integer(*level),
+                    Value::text(id),
+                    Value::text(name),
+                    Value::Number(*quantity),
+                ]
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Appends a column with a value for each record.
+pub fn add_column(flat_data: &mut FlatData, key: &'static str, values: Vec<Value<'static>>) {
+    assert_eq!(values.len(), flat_data.records.len());
+    flat_data.keys.push(Cow::from(key));
+    for (record, value) in flat_data.records.iter_mut().zip(values) {
+        record.push(value);
+    }
+}
+
+/// Folds the flat data with the fixture [`rules`].
+pub fn folded<'a>(flat_data: &'a FlatData<'a>) -> FoldedData<'a> {
+    transform(flat_data, &rules()).unwrap()
+}
@@ -26,3 +26,9 @@ pub use transform::*;
 
 mod materialize;
 pub use materialize::*;
+
+mod analyze;
+pub use analyze::*;
+
+#[cfg(test)]
+mod fixtures;
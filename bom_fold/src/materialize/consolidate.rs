@@ -0,0 +1,338 @@
+//! Merging of items listed several times under the same parent.
+
+use super::{conflict, entry_type, quantity, ChildEntry};
+use crate::{
+    find_key, position_of_key, FoldedData, Node, OutputRules, OwnedFoldedData, OwnedNode, Rules,
+    Value,
+};
+use error::{
+    error_details::{
+        bad_request::FieldViolation, precondition_failure, BadRequest, PreconditionFailure,
+    },
+    Error, Result,
+};
+use std::{borrow::Cow, cmp::Ordering};
+
+/// Separator between concatenated values if the rules don't set one.
+const DEFAULT_SEPARATOR: &str = ", ";
+
+/// Merges the items with the same ItemSync id under each parent into one, so that each produces a
+/// single BOM entry, according to the `consolidation` rules (or the defaults if they aren't set).
+/// The merged item
+/// - is placed where the first of the items was,
+/// - has the sum of the quantities, where items with an empty quantity count as 1 and other
+///   quantities that aren't numbers are reported with an `InvalidArgument` error,
+/// - has the non-empty values of the `concatenated_keys` joined by the separator,
+/// - has the children of the first of the items, which are merged in turn,
+/// - and keeps the first value of every other attribute. If the items have different values for
+///   such an attribute, other than the level or parent key, or different children, a warning is
+///   logged for each, or a `PreconditionFailure` is returned if the rules reject conflicts.
+pub fn consolidate(folded_data: &FoldedData, rules: &Rules) -> Result<OwnedFoldedData> {
+    let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+    let default_rules = Default::default();
+    let consolidation = rules.consolidation.as_ref().unwrap_or(&default_rules);
+    let keys = folded_data.attribute_keys;
+    let mut consolidator = Consolidator {
+        keys,
+        id: find_key(keys, &item_sync_rules.id_key, "id key")?,
+        position: position_of_key(keys, rules.position_key()),
+        quantity: item_sync_rules.quantity_key.as_ref().and_then(|key| position_of_key(keys, key)),
+        concatenated: consolidation
+            .concatenated_keys
+            .iter()
+            .map(|key| find_key(keys, key, "concatenated key"))
+            .collect::<Result<Vec<_>>>()?,
+        separator: consolidation.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR),
+        conflicts: Vec::new(),
+        field_violations: Vec::new(),
+    };
+    let top_level_nodes = consolidator.merge(None, folded_data.top_level_nodes.iter().collect());
+
+    let field_violations = consolidator.field_violations;
+    if !field_violations.is_empty() {
+        return Err(Error::invalid_argument_with(
+            format!("Found {} non-numeric quantities", field_violations.len()),
+            Some(BadRequest { field_violations }),
+        ));
+    }
+
+    let conflicts = consolidator.conflicts;
+    if consolidation.reject_conflicts && !conflicts.is_empty() {
+        return Err(Error::failed_precondition_with(
+            format!("Found {} conflict(s) between duplicate items", conflicts.len()),
+            Some(PreconditionFailure { violations: conflicts }),
+        ));
+    }
+    for conflict in conflicts {
+        tracing::warn!(
+            "{}: {}, keeping the first value",
+            conflict.subject.unwrap_or_default(),
+            conflict.description.unwrap_or_default()
+        );
+    }
+    Ok(OwnedFoldedData {
+        attribute_keys: keys.iter().map(|key| Cow::from(key.to_string())).collect(),
+        top_level_nodes,
+    })
+}
+
+/// Attribute positions resolved from the rules, and the conflicts found so far.
+struct Consolidator<'k> {
+    keys: &'k [Cow<'k, str>],
+    id: usize,
+    /// Position of the level or parent key, whose values differ by design.
+    position: Option<usize>,
+    quantity: Option<usize>,
+    concatenated: Vec<usize>,
+    separator: &'k str,
+    conflicts: Vec<precondition_failure::Violation>,
+    /// Duplicate lines and children of duplicate lines with non-numeric quantities.
+    field_violations: Vec<FieldViolation>,
+}
+
+impl Consolidator<'_> {
+    /// Merges the nodes with the same id, in order of first appearance.
+    fn merge(&mut self, parent: Option<&str>, nodes: Vec<&Node>) -> Vec<OwnedNode> {
+        let mut groups: Vec<(String, Vec<&Node>)> = Vec::new();
+        for node in nodes {
+            let id = node.attributes.get(self.id).map(ToString::to_string).unwrap_or_default();
+            match groups.iter_mut().find(|(group_id, _)| *group_id == id) {
+                Some((_, group)) => group.push(node),
+                None => groups.push((id, vec![node])),
+            }
+        }
+        groups.into_iter().map(|(id, group)| self.merge_group(parent, &id, &group)).collect()
+    }
+
+    fn merge_group(&mut self, parent: Option<&str>, id: &str, group: &[&Node]) -> OwnedNode {
+        let first = group[0];
+        let mut attributes = first.attributes.iter().map(Value::to_owned_value).collect::<Vec<_>>();
+        if group.len() > 1 {
+            let subject = match parent {
+                Some(parent) => format!("{parent} > {id}"),
+                None => id.to_string(),
+            };
+            tracing::info!("Merged {} lines of {subject}", group.len());
+            for (index, value) in attributes.iter_mut().enumerate() {
+                let values = group.iter().filter_map(|node| node.attributes.get(index));
+                if Some(index) == self.quantity {
+                    let quantities = group.iter().map(|node| {
+                        let field = || subject.clone();
+                        quantity(node, self.quantity, self.keys, field, &mut self.field_violations)
+                    });
+                    *value = Value::Number(quantities.sum());
+                } else if self.concatenated.contains(&index) {
+                    let values = values
+                        .map(ToString::to_string)
+                        .filter(|value| !value.trim().is_empty())
+                        .collect::<Vec<_>>();
+                    *value = Value::text_owned(values.join(self.separator));
+                } else if index != self.id && Some(index) != self.position {
+                    let mut distinct = Vec::new();
+                    for value in values.map(ToString::to_string) {
+                        if !distinct.contains(&value) {
+                            distinct.push(value);
+                        }
+                    }
+                    if distinct.len() > 1 {
+                        self.conflicts.push(precondition_failure::Violation {
+                            r#type: Some("CONFLICTING_ATTRIBUTE".to_string()),
+                            subject: Some(subject.clone()),
+                            description: Some(format!(
+                                "{:?} differs between duplicate lines: {distinct:?}",
+                                self.keys[index]
+                            )),
+                        });
+                    }
+                }
+            }
+            let first_children = self.child_entries(id, first);
+            for node in group[1..].iter() {
+                let children = self.child_entries(id, node);
+                if children != first_children {
+                    self.conflicts.push(precondition_failure::Violation {
+                        subject: Some(subject.clone()),
+                        ..conflict(&Value::text(id), &first_children, &children)
+                    });
+                }
+            }
+        }
+        let children = first.children.iter().collect();
+        OwnedNode { attributes, children: self.merge(Some(id), children) }
+    }
+
+    /// The id, entry type and quantity of each child of the item `id`, sorted so that the order
+    /// doesn't matter. Quantities that aren't numbers are reported like the merged ones.
+    fn child_entries(&mut self, id: &str, node: &Node) -> Vec<ChildEntry> {
+        let mut children = Vec::new();
+        for child in node.children.iter() {
+            let child_id =
+                child.attributes.get(self.id).map(ToString::to_string).unwrap_or_default();
+            let field = || format!("{id} > {child_id}");
+            let quantity =
+                quantity(child, self.quantity, self.keys, field, &mut self.field_violations);
+            children.push((child_id, entry_type(child), quantity));
+        }
+        children.sort_by(|lhs, rhs| lhs.partial_cmp(rhs).unwrap_or(Ordering::Equal));
+        children
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::consolidate;
+    use crate::{
+        fixtures::{add_column, flat_data, folded},
+        ConsolidationRules, FlatData, OwnedFoldedData, Rules, Value,
+    };
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    fn input(second_name: &'static str) -> FlatData<'static> {
+        let mut input = flat_data(&[
+            (0, "A-100", "Board", 1.0),
+            (1, "R-1", "Resistor", 2.0),
+            (1, "C-1", "Capacitor", 1.0),
+            (1, "R-1", second_name, 1.0),
+        ]);
+        let designators = ["", "R1, R2", "C1", "R3"];
+        add_column(&mut input, "Designator", designators.map(Value::text).to_vec());
+        input
+    }
+
+    fn rules(reject_conflicts: bool) -> Rules {
+        Rules {
+            consolidation: Some(ConsolidationRules {
+                concatenated_keys: vec!["Designator".to_string()],
+                separator: None,
+                reject_conflicts,
+            }),
+            ..crate::fixtures::rules()
+        }
+    }
+
+    fn children(owned: &OwnedFoldedData) -> Vec<Vec<String>> {
+        owned.top_level_nodes[0]
+            .children
+            .iter()
+            .map(|child| child.attributes.iter().map(ToString::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn merges_duplicate_lines() {
+        let input = input("Resistor");
+        let owned = consolidate(&folded(&input), &rules(true)).unwrap();
+        assert_eq!(
+            children(&owned),
+            [["1", "R-1", "Resistor", "3", "R1, R2, R3"], ["1", "C-1", "Capacitor", "1", "C1"]]
+        );
+    }
+
+    #[test]
+    fn flags_conflicting_attributes() {
+        let input = input("Resistor 1%");
+        let owned = consolidate(&folded(&input), &rules(false)).unwrap();
+        assert_eq!(children(&owned)[0], ["1", "R-1", "Resistor", "3", "R1, R2, R3"]);
+
+        let violations = match consolidate(&folded(&input), &rules(true)) {
+            Err(Error::FailedPrecondition(payload)) => payload.payload.unwrap().violations,
+            other => panic!("Expected failed precondition, got {other:?}"),
+        };
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].subject.as_deref(), Some("A-100 > R-1"));
+        assert_eq!(
+            violations[0].description.as_deref(),
+            Some("\"Part Name\" differs between duplicate lines: [\"Resistor\", \"Resistor 1%\"]")
+        );
+    }
+
+    #[test]
+    fn keeps_the_children_of_the_first_sub_assembly() {
+        let input = |second_bolts: f64| {
+            flat_data(&[
+                (0, "A-100", "Assembly", 1.0),
+                (1, "S-200", "Subassembly", 1.0),
+                (2, "B-300", "Bolt", 2.0),
+                (1, "S-200", "Subassembly", 2.0),
+                (2, "B-300", "Bolt", second_bolts),
+            ])
+        };
+        let lines = |owned: &OwnedFoldedData| {
+            let sub_assembly = &owned.top_level_nodes[0].children[0];
+            let mut lines = vec![sub_assembly.attributes[1..4]
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()];
+            lines.extend(
+                sub_assembly
+                    .children
+                    .iter()
+                    .map(|child| child.attributes[1..4].iter().map(ToString::to_string).collect()),
+            );
+            lines
+        };
+        let lenient = crate::fixtures::rules();
+        let strict = Rules {
+            consolidation: Some(ConsolidationRules {
+                reject_conflicts: true,
+                ..Default::default()
+            }),
+            ..crate::fixtures::rules()
+        };
+
+        let same_children = input(2.0);
+        let owned = consolidate(&folded(&same_children), &strict).unwrap();
+        assert_eq!(owned.top_level_nodes[0].children.len(), 1);
+        assert_eq!(lines(&owned), [["S-200", "Subassembly", "3"], ["B-300", "Bolt", "2"]]);
+
+        let other_children = input(3.0);
+        let owned = consolidate(&folded(&other_children), &lenient).unwrap();
+        assert_eq!(lines(&owned), [["S-200", "Subassembly", "3"], ["B-300", "Bolt", "2"]]);
+        let violations = match consolidate(&folded(&other_children), &strict) {
+            Err(Error::FailedPrecondition(payload)) => payload.payload.unwrap().violations,
+            other => panic!("Expected failed precondition, got {other:?}"),
+        };
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].r#type.as_deref(), Some("CONFLICTING_SUB_BOM"));
+        assert_eq!(violations[0].subject.as_deref(), Some("A-100 > S-200"));
+    }
+
+    #[test]
+    fn parses_and_checks_quantities() {
+        let mut input = input("Resistor");
+        input.records[1][3] = Value::text("2");
+        input.records[3][3] = Value::text(" 1 ");
+        let owned = consolidate(&folded(&input), &rules(true)).unwrap();
+        assert_eq!(children(&owned)[0], ["1", "R-1", "Resistor", "3", "R1, R2, R3"]);
+
+        input.records[3][3] = Value::text("1x");
+        let field_violations = match consolidate(&folded(&input), &rules(false)) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(field_violations.len(), 1);
+        assert_eq!(field_violations[0].field.as_deref(), Some("A-100 > R-1"));
+        assert_eq!(
+            field_violations[0].description.as_deref(),
+            Some("\"Quantity\" = \"1x\" is not a number")
+        );
+
+        // The children of duplicate sub-assemblies are compared, so their quantities are checked
+        // as well.
+        let mut input = flat_data(&[
+            (0, "A-100", "Assembly", 1.0),
+            (1, "S-200", "Subassembly", 1.0),
+            (2, "B-300", "Bolt", 2.0),
+            (1, "S-200", "Subassembly", 1.0),
+            (2, "B-300", "Bolt", 2.0),
+        ]);
+        input.records[4][3] = Value::text("2 pcs");
+        let field_violations = match consolidate(&folded(&input), &crate::fixtures::rules()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(field_violations.len(), 1);
+        assert_eq!(field_violations[0].field.as_deref(), Some("S-200 > B-300"));
+    }
+}
@@ -0,0 +1,50 @@
+//! CSV output.
+
+use crate::{materialize::ItemSyncFormat, FlatData};
+use error::Result;
+use std::path::Path;
+
+impl ItemSyncFormat<'_> {
+    /// Writes the item sync input as "boms.csv" and "bom_entries.csv" in the output directory,
+    /// with the `entry_keys` as additional columns of both.
+    pub fn write_csv(&self, output_dir: &Path) -> Result<()> {
+        let mut boms_writer = csv::Writer::from_path(output_dir.join("boms.csv"))?;
+        let headers = ["id", "name"];
+        boms_writer
+            .write_record(headers.into_iter().chain(self.entry_keys.iter().map(String::as_str)))?;
+        for bom in self.boms.iter() {
+            boms_writer.serialize((bom.id, bom.name, &bom.attributes))?;
+        }
+        boms_writer.flush()?;
+
+        let mut bom_entries_writer = csv::Writer::from_path(output_dir.join("bom_entries.csv"))?;
+        let headers = ["bom_id", "entry_type", "entry_id", "quantity"];
+        bom_entries_writer
+            .write_record(headers.into_iter().chain(self.entry_keys.iter().map(String::as_str)))?;
+        for entry in self.bom_entries.iter() {
+            // Tuples don't write headers, unlike the record itself.
+            bom_entries_writer.serialize((
+                entry.bom_id,
+                entry.entry_type,
+                entry.entry_id,
+                entry.quantity,
+                &entry.attributes,
+            ))?;
+        }
+        bom_entries_writer.flush()?;
+        Ok(())
+    }
+}
+
+impl FlatData<'_> {
+    /// Writes the keys as the header row followed by the records to a CSV file.
+    pub fn write_csv(&self, path: &Path) -> Result<()> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(self.keys.iter().map(|key| key.as_bytes()))?;
+        for record in self.records.iter() {
+            writer.write_record(record.iter().map(ToString::to_string))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+//! Nested JSON representation of the item hierarchy.
+//!
+//! The hierarchy is written as
+//!
+//! ```json
+//! {
+//!   "attribute_keys": ["Part Number", "Quantity"],
+//!   "top_level_nodes": [
+//!     {
+//!       "attributes": { "Part Number": "A-100", "Quantity": 1.0 },
+//!       "children": [{ "attributes": { "Part Number": "2001", "Quantity": 4.0 }, "children": [] }]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! with the attributes keyed by the `attribute_keys` rather than positional.
+
+use crate::{FoldedData, Node, OwnedFoldedData, OwnedNode, Value};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+impl FoldedData<'_> {
+    /// Serializes the hierarchy as pretty printed nested JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| Error::internal(format!("Could not serialize folded data: {e}")))
+    }
+
+    /// Writes the hierarchy as nested JSON to a file.
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+}
+
+impl Serialize for FoldedData<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("FoldedData", 2)?;
+        state.serialize_field("attribute_keys", self.attribute_keys)?;
+        state.serialize_field(
+            "top_level_nodes",
+            &JsonNodes(self.attribute_keys, &self.top_level_nodes),
+        )?;
+        state.end()
+    }
+}
+
+/// Serializes the nodes as a sequence of `{"attributes": {..}, "children": [..]}` objects.
+struct JsonNodes<'a>(&'a [Cow<'a, str>], &'a [Node<'a>]);
+
+impl Serialize for JsonNodes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let JsonNodes(keys, nodes) = *self;
+        serializer.collect_seq(nodes.iter().map(|node| JsonNode(keys, node)))
+    }
+}
+
+struct JsonNode<'a>(&'a [Cow<'a, str>], &'a Node<'a>);
+
+impl Serialize for JsonNode<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let JsonNode(keys, node) = *self;
+        let mut state = serializer.serialize_struct("Node", 2)?;
+        state.serialize_field("attributes", &JsonAttributes(keys, node.attributes))?;
+        state.serialize_field("children", &JsonNodes(keys, &node.children))?;
+        state.end()
+    }
+}
+
+/// Serializes the attributes as a map from key to bare value, in the order of the keys.
+struct JsonAttributes<'a>(&'a [Cow<'a, str>], &'a [Value<'a>]);
+
+impl Serialize for JsonAttributes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let JsonAttributes(keys, attributes) = *self;
+        serializer.collect_map(keys.iter().zip(attributes))
+    }
+}
+
+/// Deserialized form of the JSON, before the attributes are made positional.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFoldedData {
+    #[serde(default)]
+    attribute_keys: Vec<String>,
+    top_level_nodes: Vec<RawNode>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawNode {
+    #[serde(default)]
+    attributes: BTreeMap<String, RawValue>,
+    #[serde(default)]
+    children: Vec<RawNode>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawValue {
+    // Integers are tried first, since numbers written as `Number` always have a fraction, e.g.
+    // `2.0`.
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+    Text(String),
+    Null(()),
+}
+
+impl OwnedFoldedData {
+    /// Reads a hierarchy from the nested JSON written by [`FoldedData::to_json`].
+    ///
+    /// `attribute_keys` may be left out, in which case the keys are collected from the attributes
+    /// of the nodes in alphabetical order. Attributes missing from a node and `null` values are read
+    /// as `Value::Empty`.
+    pub fn from_json(contents: &str) -> Result<OwnedFoldedData> {
+        let mut track = serde_path_to_error::Track::new();
+        let mut deserializer = serde_json::Deserializer::from_str(contents);
+        let raw = RawFoldedData::deserialize(serde_path_to_error::Deserializer::new(
+            &mut deserializer,
+            &mut track,
+        ))
+        .map_err(|e| {
+            let field = track.path().to_string();
+            let description = e.to_string();
+            Error::invalid_argument_with(
+                format!("Invalid folded data JSON at {field}: {description}"),
+                Some(BadRequest {
+                    field_violations: vec![FieldViolation {
+                        field: Some(field),
+                        description: Some(description),
+                    }],
+                }),
+            )
+        })?;
+
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = raw.attribute_keys.iter().find(|key| !seen.insert(key.as_str())) {
+            return Err(Error::invalid_argument(format!(
+                "Invalid folded data JSON: duplicate attribute key {duplicate:?}"
+            )));
+        }
+        let mut attribute_keys = raw.attribute_keys.clone();
+        collect_keys(&raw.top_level_nodes, &mut seen, &mut attribute_keys);
+
+        Ok(OwnedFoldedData {
+            top_level_nodes: raw
+                .top_level_nodes
+                .into_iter()
+                .map(|node| owned_node(node, &attribute_keys))
+                .collect(),
+            attribute_keys: attribute_keys.into_iter().map(Cow::from).collect(),
+        })
+    }
+
+    /// Reads a hierarchy from a nested JSON file.
+    pub fn from_json_path(path: &Path) -> Result<OwnedFoldedData> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+}
+
+/// Appends the attribute keys of the nodes and their descendents that aren't `seen` yet.
+fn collect_keys<'a>(nodes: &'a [RawNode], seen: &mut HashSet<&'a str>, keys: &mut Vec<String>) {
+    for node in nodes {
+        for key in node.attributes.keys() {
+            if seen.insert(key) {
+                keys.push(key.clone());
+            }
+        }
+        collect_keys(&node.children, seen, keys);
+    }
+}
+
+/// Orders the attributes of the node and its descendents by the keys.
+fn owned_node(mut node: RawNode, keys: &[String]) -> OwnedNode {
+    OwnedNode {
+        attributes: keys
+            .iter()
+            .map(|key| match node.attributes.remove(key) {
+                Some(RawValue::Integer(integer)) => Value::Integer(integer),
+                Some(RawValue::Number(number)) => Value::Number(number),
+                Some(RawValue::Boolean(boolean)) => Value::Boolean(boolean),
+                Some(RawValue::Text(text)) => Value::Text(Cow::from(text)),
+                Some(RawValue::Null(())) | None => Value::Empty,
+            })
+            .collect(),
+        children: node.children.into_iter().map(|child| owned_node(child, keys)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FlatData, FoldedData, Node, OwnedFoldedData, Value};
+    use error::Error;
+    use pretty_assertions::assert_eq;
+    use std::borrow::Cow;
+
+    #[test]
+    fn round_trips() {
+        let flat_data = FlatData {
+            keys: vec![Cow::from("Part Number"), Cow::from("Quantity")],
+            records: vec![
+                vec![Value::text("A-100"), Value::Number(1.0)],
+                vec![Value::text("2001"), Value::Number(4.0)],
+            ],
+            ..Default::default()
+        };
+        let folded_data = FoldedData {
+            attribute_keys: &flat_data.keys,
+            top_level_nodes: vec![Node {
+                attributes: &flat_data.records[0],
+                children: vec![Node { attributes: &flat_data.records[1], children: vec![] }],
+            }],
+        };
+        let json = folded_data.to_json().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "attribute_keys": ["Part Number", "Quantity"],
+                "top_level_nodes": [{
+                    "attributes": { "Part Number": "A-100", "Quantity": 1.0 },
+                    "children": [{
+                        "attributes": { "Part Number": "2001", "Quantity": 4.0 },
+                        "children": []
+                    }]
+                }]
+            })
+        );
+        assert_eq!(OwnedFoldedData::from_json(&json).unwrap().folded_data(), folded_data);
+    }
+
+    #[test]
+    fn collects_missing_attribute_keys() {
+        let owned = OwnedFoldedData::from_json(
+            r#"{
+                "top_level_nodes": [{
+                    "attributes": { "Part Number": "A-100", "Name": null },
+                    "children": [{ "attributes": { "Part Number": "2001", "Quantity": 4 } }]
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(owned.attribute_keys, ["Name", "Part Number", "Quantity"]);
+        assert_eq!(
+            owned.top_level_nodes[0].attributes,
+            [Value::Empty, Value::text("A-100"), Value::Empty]
+        );
+        assert_eq!(
+            owned.top_level_nodes[0].children[0].attributes,
+            [Value::Empty, Value::text("2001"), Value::Integer(4)]
+        );
+    }
+
+    #[test]
+    fn reports_path_to_bad_value() {
+        let result = OwnedFoldedData::from_json(
+            r#"{ "top_level_nodes": [{ "attributes": { "Part Number": ["A-100"] } }] }"#,
+        );
+        let message = match result {
+            Err(Error::InvalidArgument(payload)) => payload.message,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert!(
+            message.starts_with(
+                "Invalid folded data JSON at top_level_nodes[0].attributes.Part Number"
+            ),
+            "{message}"
+        );
+    }
+}
@@ -1,15 +1,33 @@
 //! Converts the in memory representation to serialized format.
 
-use crate::{FoldedData, ItemSyncFormatRules, Node, Value};
-use error::{Error, Result};
+mod consolidate;
+mod csv;
+mod json;
+mod tree;
+mod xlsx;
+
+pub use consolidate::*;
+pub use tree::*;
+
+use crate::{find_key, position_of_key, FoldedData, ItemSyncFormatRules, Node, Value};
+use error::{
+    error_details::{
+        bad_request::FieldViolation, precondition_failure, BadRequest, PreconditionFailure,
+    },
+    Error, Result,
+};
 use serde::Serialize;
-use std::cmp::Ordering;
+use std::{borrow::Cow, cmp::Ordering, collections::HashMap};
 
 /// Serializable output format compatible with the "item sync" input flat file.
 #[derive(Debug, Serialize)]
 pub struct ItemSyncFormat<'a> {
     pub boms: Vec<BomRecord<'a>>,
     pub bom_entries: Vec<BomEntryRecord<'a>>,
+    /// Keys of the additional attributes written after the name of each BOM and the quantity of
+    /// each BOM entry.
+    #[serde(skip)]
+    pub entry_keys: Vec<String>,
 }
 
 /// Serializable output meant to populate the "BOMs" sheet of the item sync input flat file.
@@ -17,6 +35,9 @@ pub struct ItemSyncFormat<'a> {
 pub struct BomRecord<'a> {
     id: &'a Value<'a>,
     name: &'a Value<'a>,
+    /// Values of the `entry_keys` of the BOM's item, where it first appears.
+    #[serde(skip)]
+    attributes: Vec<&'a Value<'a>>,
 }
 
 /// Serializable output meant to populate the "BOM entries" sheet of the item sync input flat file.
@@ -26,64 +47,111 @@ pub struct BomEntryRecord<'a> {
     entry_type: &'a str,
     entry_id: &'a Value<'a>,
     quantity: f64,
+    /// Values of the `entry_keys` of the entry's item, empty where the item doesn't have one.
+    #[serde(skip)]
+    attributes: Vec<&'a Value<'a>>,
 }
 
 impl ItemSyncFormat<'_> {
     /// Converts the folded data into a serializable format compatible with the item sync flat
     /// file.
+    ///
+    /// A sub-BOM that appears in several places is only listed once, along with its entries. If the
+    /// occurrences have different children (by id, entry type and quantity), the sub-BOM is
+    /// ambiguous and a `PreconditionFailure` naming the sub-BOM and the differing children is
+    /// returned. Empty quantities count as 1, while an `InvalidArgument` error listing every other
+    /// quantity that isn't a number is returned before that.
     pub fn format_item_sync<'a>(
         folded_data: &'a FoldedData,
         rules: &ItemSyncFormatRules,
     ) -> Result<ItemSyncFormat<'a>> {
-        let indices = AttributeIndices::new(folded_data, rules)?;
+        Self::format_item_sync_with_attributes(folded_data, rules, &[])
+    }
+
+    /// Like [`ItemSyncFormat::format_item_sync`], but also writes the values of the `entry_keys`,
+    /// e.g. rolled up costs, as additional columns of the BOMs and BOM entries. The BOMs are the
+    /// only place these appear for top level items, which aren't an entry of another BOM.
+    pub fn format_item_sync_with_attributes<'a>(
+        folded_data: &'a FoldedData,
+        rules: &ItemSyncFormatRules,
+        entry_keys: &[String],
+    ) -> Result<ItemSyncFormat<'a>> {
+        let mut indices = AttributeIndices::new(folded_data, rules)?;
+        indices.entry_attributes = entry_keys
+            .iter()
+            .map(|key| find_key(folded_data.attribute_keys, key, "entry key"))
+            .collect::<Result<_>>()?;
 
-        let mut boms = Vec::new();
-        let mut bom_entries = Vec::new();
+        let mut builder = RecordBuilder {
+            keys: folded_data.attribute_keys,
+            indices: &indices,
+            boms: Vec::new(),
+            bom_entries: Vec::new(),
+            children_by_bom: HashMap::new(),
+            conflicts: Vec::new(),
+            field_violations: Vec::new(),
+        };
         for node in folded_data.top_level_nodes.iter() {
-            Self::recursively_make_records(
-                &mut boms,
-                &mut bom_entries,
-                &indices,
-                node,
-                /*parent_node=*/ None,
-            )?;
+            builder.add(node, /*parent_node_id=*/ None)?;
+        }
+        if !builder.field_violations.is_empty() {
+            return Err(Error::invalid_argument_with(
+                format!("Found {} non-numeric quantities", builder.field_violations.len()),
+                Some(BadRequest { field_violations: builder.field_violations }),
+            ));
         }
-        boms.sort_unstable_by(|lhs, rhs| lhs.id.partial_cmp(rhs.id).unwrap_or(Ordering::Equal));
+        if !builder.conflicts.is_empty() {
+            return Err(Error::failed_precondition_with(
+                format!(
+                    "Found {} sub-BOM(s) with conflicting children in different places",
+                    builder.conflicts.len()
+                ),
+                Some(PreconditionFailure { violations: builder.conflicts }),
+            ));
+        }
+        let mut boms = builder.boms;
+        // The sort is stable so that the first occurrence of each BOM is kept.
+        boms.sort_by(|lhs, rhs| lhs.id.partial_cmp(rhs.id).unwrap_or(Ordering::Equal));
         boms.dedup_by_key(|b| b.id);
-        Ok(ItemSyncFormat { boms, bom_entries })
+        Ok(ItemSyncFormat {
+            boms,
+            bom_entries: builder.bom_entries,
+            entry_keys: entry_keys.to_vec(),
+        })
     }
+}
+
+/// Child of a sub-BOM as it's compared between occurrences: id, entry type and quantity.
+type ChildEntry = (String, &'static str, f64);
+
+/// Accumulates the records while walking the hierarchy.
+struct RecordBuilder<'a, 'i> {
+    keys: &'a [Cow<'a, str>],
+    indices: &'i AttributeIndices,
+    boms: Vec<BomRecord<'a>>,
+    bom_entries: Vec<BomEntryRecord<'a>>,
+    /// Children of each sub-BOM added so far, keyed by the id of the sub-BOM.
+    children_by_bom: HashMap<String, Vec<ChildEntry>>,
+    conflicts: Vec<precondition_failure::Violation>,
+    /// Entries whose quantity isn't a number.
+    field_violations: Vec<FieldViolation>,
+}
 
+impl<'a> RecordBuilder<'a, '_> {
     /// Adds boms to the bom list and entries to the entry list via DFS.
     /// If an item has any children, it is assumed to be a "sub-bom". If it has no children it is
     /// assumed to be a "part".
-    fn recursively_make_records<'a>(
-        boms: &mut Vec<BomRecord<'a>>,
-        bom_entries: &mut Vec<BomEntryRecord<'a>>,
-        indices: &AttributeIndices,
-        node: &'a Node,
-        parent_node_id: Option<&'a Value<'a>>,
-    ) -> Result<()> {
-        let entry_type = match node.children.is_empty() {
-            true => "part",
-            false => "sub-bom",
-        };
-        let node_id = node
-            .attributes
-            .get(indices.id)
-            .ok_or_else(|| Error::invalid_argument("Node is missing id"))?;
+    fn add(&mut self, node: &'a Node, parent_node_id: Option<&'a Value<'a>>) -> Result<()> {
+        let node_id = self.id(node)?;
         // Add as child of parent node.
         if let Some(parent_node_id) = parent_node_id {
-            bom_entries.push(BomEntryRecord {
+            let quantity = self.quantity(node, parent_node_id, node_id);
+            self.bom_entries.push(BomEntryRecord {
                 bom_id: parent_node_id,
-                entry_type,
+                entry_type: entry_type(node),
                 entry_id: node_id,
-                quantity: indices
-                    .quantity
-                    .and_then(|index| match node.attributes.get(index) {
-                        Some(Value::Number(n)) => Some(*n),
-                        _ => None,
-                    })
-                    .unwrap_or(1.0),
+                quantity,
+                attributes: self.entry_attributes(node),
             });
         }
         if node.children.is_empty() {
@@ -92,14 +160,131 @@ impl ItemSyncFormat<'_> {
 
         let name = node
             .attributes
-            .get(indices.name)
+            .get(self.indices.name)
             .ok_or_else(|| Error::invalid_argument("Unable to find name field in BOM node."))?;
-        boms.push(BomRecord { id: node_id, name });
+        self.boms.push(BomRecord { id: node_id, name, attributes: self.entry_attributes(node) });
+
+        let mut children: Vec<ChildEntry> = Vec::new();
         for child in node.children.iter() {
-            Self::recursively_make_records(boms, bom_entries, indices, child, Some(node_id))?;
+            let child_id = self.id(child)?;
+            let quantity = self.quantity(child, node_id, child_id);
+            children.push((child_id.to_string(), entry_type(child), quantity));
+        }
+        children.sort_by(|lhs, rhs| lhs.partial_cmp(rhs).unwrap_or(Ordering::Equal));
+        match self.children_by_bom.get(&node_id.to_string()) {
+            // Identical copies collapse to the entries of the first one, but their descendents are
+            // still compared.
+            Some(first) => {
+                if *first != children {
+                    self.conflicts.push(conflict(node_id, first, &children));
+                }
+                for child in node.children.iter() {
+                    self.add_descendents(child)?;
+                }
+            }
+            None => {
+                self.children_by_bom.insert(node_id.to_string(), children);
+                for child in node.children.iter() {
+                    self.add(child, Some(node_id))?;
+                }
+            }
         }
         Ok(())
     }
+
+    /// Adds the sub-BOMs below the node, whose own entry was already added.
+    fn add_descendents(&mut self, node: &'a Node) -> Result<()> {
+        match node.children.is_empty() {
+            true => Ok(()),
+            false => self.add(node, None),
+        }
+    }
+
+    /// Values of the `entry_keys` of the node, with an empty value for each the node doesn't have
+    /// so that the columns stay aligned.
+    fn entry_attributes(&self, node: &'a Node) -> Vec<&'a Value<'a>> {
+        self.indices
+            .entry_attributes
+            .iter()
+            .map(|index| node.attributes.get(*index).unwrap_or(&Value::Empty))
+            .collect()
+    }
+
+    /// Quantity of the node as an entry of the parent BOM, see [`quantity`].
+    fn quantity(&mut self, node: &Node, parent_id: &Value, id: &Value) -> f64 {
+        let field = || format!("{parent_id} > {id}");
+        quantity(node, self.indices.quantity, self.keys, field, &mut self.field_violations)
+    }
+
+    fn id(&self, node: &'a Node) -> Result<&'a Value<'a>> {
+        node.attributes
+            .get(self.indices.id)
+            .ok_or_else(|| Error::invalid_argument("Node is missing id"))
+    }
+}
+
+/// Quantity of the node, the value at `quantity_index` parsed as a number, which defaults to 1 if
+/// it's empty or missing. An explicit 0 is kept. If the quantity isn't a number, a violation of the
+/// `field` is added to the `field_violations` (unless it's already there) and 1 is returned, so that
+/// all of them are reported at once.
+fn quantity(
+    node: &Node,
+    quantity_index: Option<usize>,
+    keys: &[Cow<str>],
+    field: impl FnOnce() -> String,
+    field_violations: &mut Vec<FieldViolation>,
+) -> f64 {
+    let Some(index) = quantity_index else { return 1.0 };
+    match node.attributes.get(index) {
+        Some(value) if !value.is_empty() => crate::analyze::number(value).unwrap_or_else(|| {
+            let violation = FieldViolation {
+                field: Some(field()),
+                description: Some(format!(
+                    "{:?} = {:?} is not a number",
+                    keys[index],
+                    value.to_string()
+                )),
+            };
+            if !field_violations.contains(&violation) {
+                field_violations.push(violation);
+            }
+            1.0
+        }),
+        _ => 1.0,
+    }
+}
+
+/// Entry type of the node: "sub-bom" if it has any children, or else "part".
+fn entry_type(node: &Node) -> &'static str {
+    match node.children.is_empty() {
+        true => "part",
+        false => "sub-bom",
+    }
+}
+
+/// Describes the children that differ between two occurrences of a sub-BOM.
+fn conflict(
+    id: &Value,
+    first: &[ChildEntry],
+    other: &[ChildEntry],
+) -> precondition_failure::Violation {
+    let describe = |entries: &[ChildEntry], others: &[ChildEntry]| {
+        entries
+            .iter()
+            .filter(|entry| !others.contains(entry))
+            .map(|(id, entry_type, quantity)| format!("{id} ({entry_type} x{quantity})"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    precondition_failure::Violation {
+        r#type: Some("CONFLICTING_SUB_BOM".to_string()),
+        subject: Some(id.to_string()),
+        description: Some(format!(
+            "Children differ between occurrences, first has [{}] where another has [{}]",
+            describe(first, other),
+            describe(other, first)
+        )),
+    }
 }
 
 /// Positions of relevant attributes in the Node attribute vectors.
@@ -107,25 +292,148 @@ struct AttributeIndices {
     id: usize,
     name: usize,
     quantity: Option<usize>,
+    /// Attributes written after the quantity of each BOM entry.
+    entry_attributes: Vec<usize>,
 }
 
 impl AttributeIndices {
     fn new(folded_data: &FoldedData, rules: &ItemSyncFormatRules) -> Result<Self> {
-        let id_key = &rules.id_key;
-        let id_index = folded_data
-            .attribute_keys
-            .iter()
-            .position(|a| a == id_key)
-            .ok_or_else(|| Error::invalid_argument("id_key not found in folded data"))?;
+        let keys = folded_data.attribute_keys;
+        let id_index = find_key(keys, &rules.id_key, "id key")?;
         let name_index = rules
             .name_key
             .as_ref()
-            .and_then(|name_key| folded_data.attribute_keys.iter().position(|a| a == name_key))
+            .and_then(|name_key| position_of_key(keys, name_key))
             .unwrap_or(id_index);
-        let quantity_index = rules
-            .quantity_key
-            .as_ref()
-            .and_then(|key| folded_data.attribute_keys.iter().position(|a| a == key));
-        Ok(Self { id: id_index, name: name_index, quantity: quantity_index })
+        let quantity_index = rules.quantity_key.as_ref().and_then(|key| position_of_key(keys, key));
+        Ok(Self {
+            id: id_index,
+            name: name_index,
+            quantity: quantity_index,
+            entry_attributes: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        fixtures::{add_column, flat_data, folded, item_sync_rules},
+        FlatData, ItemSyncFormat, Value,
+    };
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    /// "A-100" contains "S-200" twice, with "B-300" as the child of each.
+    fn input(bolt_quantity: f64) -> FlatData<'static> {
+        flat_data(&[
+            (0, "A-100", "Assembly", 1.0),
+            (1, "S-200", "Subassembly", 2.0),
+            (2, "B-300", "Bolt", 2.0),
+            (1, "S-200", "Subassembly", 2.0),
+            (2, "B-300", "Bolt", bolt_quantity),
+        ])
+    }
+
+    #[test]
+    fn collapses_identical_sub_boms() {
+        let input = input(2.0);
+        let folded_data = folded(&input);
+        let formatted_data =
+            ItemSyncFormat::format_item_sync(&folded_data, &item_sync_rules()).unwrap();
+        let boms = formatted_data.boms.iter().map(|bom| bom.id.to_string()).collect::<Vec<_>>();
+        assert_eq!(boms, ["A-100", "S-200"]);
+        let entries = formatted_data
+            .bom_entries
+            .iter()
+            .map(|entry| format!("{} {} {}", entry.bom_id, entry.entry_id, entry.quantity))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, ["A-100 S-200 2", "S-200 B-300 2", "A-100 S-200 2"]);
+    }
+
+    #[test]
+    fn rejects_conflicting_sub_boms() {
+        let input = input(3.0);
+        let folded_data = folded(&input);
+        let violations = match ItemSyncFormat::format_item_sync(&folded_data, &item_sync_rules()) {
+            Err(Error::FailedPrecondition(payload)) => payload.payload.unwrap().violations,
+            other => panic!("Expected failed precondition, got {other:?}"),
+        };
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].subject.as_deref(), Some("S-200"));
+        assert_eq!(
+            violations[0].description.as_deref(),
+            Some(
+                "Children differ between occurrences, first has [B-300 (part x2)] where another \
+                 has [B-300 (part x3)]"
+            )
+        );
+    }
+
+    #[test]
+    fn defaults_empty_quantities() {
+        let mut input = flat_data(&[
+            (0, "A-100", "Assembly", 1.0),
+            (1, "B-300", "Bolt", 1.0),
+            (1, "N-400", "Nut", 1.0),
+        ]);
+        let quantity = input.keys.iter().position(|key| key == "Quantity").unwrap();
+        for (record, value) in
+            input.records.iter_mut().zip([Value::Empty, Value::Empty, Value::Integer(0)])
+        {
+            record[quantity] = value;
+        }
+        let folded_data = folded(&input);
+        let formatted_data =
+            ItemSyncFormat::format_item_sync(&folded_data, &item_sync_rules()).unwrap();
+        let quantities =
+            formatted_data.bom_entries.iter().map(|entry| entry.quantity).collect::<Vec<_>>();
+        assert_eq!(quantities, [1.0, 0.0]);
+    }
+
+    #[test]
+    fn rejects_non_numeric_quantities() {
+        let mut input = input(2.0);
+        input.records[2][3] = Value::text("2 pcs");
+        let folded_data = folded(&input);
+        let field_violations =
+            match ItemSyncFormat::format_item_sync(&folded_data, &item_sync_rules()) {
+                Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+                other => panic!("Expected invalid argument, got {other:?}"),
+            };
+        assert_eq!(field_violations.len(), 1);
+        assert_eq!(field_violations[0].field.as_deref(), Some("S-200 > B-300"));
+        assert_eq!(
+            field_violations[0].description.as_deref(),
+            Some("\"Quantity\" = \"2 pcs\" is not a number")
+        );
+    }
+
+    #[test]
+    fn writes_attributes_of_boms_and_entries() {
+        let mut input = input(2.0);
+        let costs = [12.0, 4.0, 2.0, 4.0, 2.0].map(Value::Number).to_vec();
+        add_column(&mut input, "Extended Unit Cost", costs);
+        // Records can be shorter than the keys, e.g. for ragged worksheet rows.
+        input.records[2].pop();
+        let folded_data = folded(&input);
+        let formatted_data = ItemSyncFormat::format_item_sync_with_attributes(
+            &folded_data,
+            &item_sync_rules(),
+            &["Extended Unit Cost".to_string()],
+        )
+        .unwrap();
+        let boms = formatted_data
+            .boms
+            .iter()
+            .map(|bom| format!("{} {:?}", bom.id, bom.attributes))
+            .collect::<Vec<_>>();
+        assert_eq!(boms, ["A-100 [Number(12.0)]", "S-200 [Number(4.0)]"]);
+        let entries = formatted_data
+            .bom_entries
+            .iter()
+            .map(|entry| format!("{} {:?}", entry.entry_id, entry.attributes))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, ["S-200 [Number(4.0)]", "B-300 [Empty]", "S-200 [Number(4.0)]"]);
     }
 }
@@ -0,0 +1,264 @@
+//! Human readable rendering of the item hierarchy.
+
+use crate::{find_key, position_of_key, FoldedData, Node};
+use error::{Error, Result};
+use std::fmt::Write;
+
+/// Determines what `render_tree` prints.
+#[derive(Debug, Default, Clone)]
+pub struct TreeOptions {
+    /// Keys of the attributes printed for each item, in order, e.g. `["Part Number", "Part
+    /// Name"]`.
+    pub columns: Vec<String>,
+
+    /// Keys of the attributes printed after the `columns` if the hierarchy has them, e.g. the
+    /// optional name key of the rules. Unlike the `columns`, missing keys are left out.
+    pub optional_columns: Vec<String>,
+
+    /// Key of the quantity attribute, printed after the columns as e.g. "(x4)". Left out if the
+    /// hierarchy doesn't have it.
+    pub quantity_key: Option<String>,
+
+    /// Items deeper than this are left out, where the top level (or selected) items have depth 0.
+    /// Items with left out descendents are marked with the number of descendents, e.g. "[+12]".
+    pub max_depth: Option<usize>,
+
+    /// If set, only the subtrees of the items matching the selection are printed.
+    pub subtree: Option<SubtreeSelection>,
+}
+
+/// Selects the items whose `key` attribute is `value`, e.g. the "Part Number" "A-100".
+#[derive(Debug, Clone)]
+pub struct SubtreeSelection {
+    pub key: String,
+    pub value: String,
+}
+
+/// Renders the hierarchy as an indented tree drawn with box-drawing characters, one item per line:
+///
+/// ```text
+/// A-100  Assembly (x1)
+/// ├── 2001  Bracket (x4)
+/// │   └── B-300  Bolt (x2)
+/// └── N-400  Nut (x8)
+/// ```
+pub fn render_tree(folded_data: &FoldedData, options: &TreeOptions) -> Result<String> {
+    let keys = folded_data.attribute_keys;
+    let columns = options
+        .columns
+        .iter()
+        .map(|column| find_key(keys, column, "column"))
+        .chain(
+            options
+                .optional_columns
+                .iter()
+                .filter_map(|column| position_of_key(keys, column))
+                .map(Ok),
+        )
+        .collect::<Result<Vec<_>>>()?;
+    let quantity = options.quantity_key.as_ref().and_then(|key| position_of_key(keys, key));
+    let renderer = Renderer { columns, quantity, max_depth: options.max_depth };
+
+    let roots = match options.subtree {
+        Some(ref selection) => {
+            let index = find_key(keys, &selection.key, "subtree key")?;
+            let mut roots = Vec::new();
+            for node in folded_data.top_level_nodes.iter() {
+                find_subtrees(node, index, selection.value.trim(), &mut roots);
+            }
+            if roots.is_empty() {
+                return Err(Error::not_found(format!(
+                    "No item has {:?} = {:?}",
+                    selection.key, selection.value
+                )));
+            }
+            roots
+        }
+        None => folded_data.top_level_nodes.iter().collect(),
+    };
+
+    let mut output = String::new();
+    for root in roots {
+        renderer.render(&mut output, root, "", None, 0);
+    }
+    Ok(output)
+}
+
+/// Collects the outermost nodes whose attribute at `index` is `value`.
+fn find_subtrees<'a>(node: &'a Node<'a>, index: usize, value: &str, roots: &mut Vec<&'a Node<'a>>) {
+    if node.attributes.get(index).map(|attribute| attribute.to_string()).as_deref().map(str::trim)
+        == Some(value)
+    {
+        roots.push(node);
+        return;
+    }
+    for child in node.children.iter() {
+        find_subtrees(child, index, value, roots);
+    }
+}
+
+/// Attribute positions and limits resolved from the `TreeOptions`.
+struct Renderer {
+    columns: Vec<usize>,
+    quantity: Option<usize>,
+    max_depth: Option<usize>,
+}
+
+impl Renderer {
+    /// Renders the node and its descendents. `prefix` is the indentation drawn for the ancestors
+    /// and `is_last` says whether the node is the last of its siblings, or `None` for roots.
+    fn render(
+        &self,
+        output: &mut String,
+        node: &Node,
+        prefix: &str,
+        is_last: Option<bool>,
+        depth: usize,
+    ) {
+        let (branch, child_prefix) = match is_last {
+            None => ("", String::new()),
+            Some(false) => ("├── ", format!("{prefix}│   ")),
+            Some(true) => ("└── ", format!("{prefix}    ")),
+        };
+        let mut line = self
+            .columns
+            .iter()
+            .map(|index| node.attributes.get(*index).map(ToString::to_string).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("  ");
+        let quantity = self.quantity.and_then(|index| node.attributes.get(index));
+        if let Some(quantity) = quantity.filter(|quantity| !quantity.is_empty()) {
+            // `write!` to a `String` can't fail.
+            let _ = write!(line, " (x{quantity})");
+        }
+
+        let hide_children = matches!(self.max_depth, Some(max_depth) if depth >= max_depth);
+        if hide_children && !node.children.is_empty() {
+            let _ = write!(line, " [+{}]", count_descendents(node));
+        }
+        let _ = writeln!(output, "{prefix}{branch}{}", line.trim_end());
+        if hide_children {
+            return;
+        }
+        for (index, child) in node.children.iter().enumerate() {
+            let is_last = index + 1 == node.children.len();
+            self.render(output, child, &child_prefix, Some(is_last), depth + 1);
+        }
+    }
+}
+
+/// Counts all of the nodes below the node.
+fn count_descendents(node: &Node) -> usize {
+    node.children.iter().map(|child| 1 + count_descendents(child)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_tree, SubtreeSelection, TreeOptions};
+    use crate::{FlatData, FoldedData, Node, Value};
+    use error::Error;
+    use pretty_assertions::assert_eq;
+    use std::borrow::Cow;
+
+    fn input() -> FlatData<'static> {
+        FlatData {
+            keys: ["Part Number", "Part Name", "Quantity"].into_iter().map(Cow::from).collect(),
+            records: [
+                ("A-100", "Assembly", 1.0),
+                ("2001", "Bracket", 4.0),
+                ("B-300", "Bolt", 2.0),
+                ("N-400", "Nut", 8.0),
+                ("C-500", "Cover", 1.0),
+            ]
+            .into_iter()
+            .map(|(number, name, quantity)| {
+                vec![Value::text(number), Value::text(name), Value::Number(quantity)]
+            })
+            .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn folded<'a>(input: &'a FlatData<'a>) -> FoldedData<'a> {
+        let leaf = |index: usize| Node { attributes: &input.records[index], children: Vec::new() };
+        FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![
+                Node {
+                    attributes: &input.records[0],
+                    children: vec![
+                        Node { attributes: &input.records[1], children: vec![leaf(2)] },
+                        leaf(3),
+                    ],
+                },
+                leaf(4),
+            ],
+        }
+    }
+
+    fn options() -> TreeOptions {
+        TreeOptions {
+            columns: vec!["Part Number".to_string(), "Part Name".to_string()],
+            quantity_key: Some("Quantity".to_string()),
+            ..TreeOptions::default()
+        }
+    }
+
+    #[test]
+    fn renders_tree() {
+        let input = input();
+        assert_eq!(
+            render_tree(&folded(&input), &options()).unwrap(),
+            "A-100  Assembly (x1)\n\
+             ├── 2001  Bracket (x4)\n\
+             │   └── B-300  Bolt (x2)\n\
+             └── N-400  Nut (x8)\n\
+             C-500  Cover (x1)\n"
+        );
+    }
+
+    #[test]
+    fn skips_missing_optional_keys() {
+        let input = input();
+        let options = TreeOptions {
+            columns: vec!["Part Number".to_string()],
+            optional_columns: vec!["Description".to_string(), "Part Name".to_string()],
+            quantity_key: Some("Qty".to_string()),
+            max_depth: Some(0),
+            ..TreeOptions::default()
+        };
+        assert_eq!(
+            render_tree(&folded(&input), &options).unwrap(),
+            "A-100  Assembly [+3]\nC-500  Cover\n"
+        );
+
+        let options = TreeOptions { columns: vec!["Description".to_string()], ..options };
+        assert!(matches!(render_tree(&folded(&input), &options), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn limits_depth() {
+        let input = input();
+        let options = TreeOptions { max_depth: Some(0), quantity_key: None, ..options() };
+        assert_eq!(
+            render_tree(&folded(&input), &options).unwrap(),
+            "A-100  Assembly [+3]\nC-500  Cover\n"
+        );
+    }
+
+    #[test]
+    fn selects_subtree() {
+        let input = input();
+        let subtree =
+            SubtreeSelection { key: "Part Number".to_string(), value: "2001".to_string() };
+        let options = TreeOptions { subtree: Some(subtree), ..options() };
+        assert_eq!(
+            render_tree(&folded(&input), &options).unwrap(),
+            "2001  Bracket (x4)\n└── B-300  Bolt (x2)\n"
+        );
+
+        let subtree = SubtreeSelection { key: "Part Number".to_string(), value: "X".to_string() };
+        let options = TreeOptions { subtree: Some(subtree), ..options };
+        assert!(matches!(render_tree(&folded(&input), &options), Err(Error::NotFound(_))));
+    }
+}
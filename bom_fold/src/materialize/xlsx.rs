@@ -0,0 +1,240 @@
+//! XLSX output.
+
+use crate::{materialize::ItemSyncFormat, FlatData, Value};
+use error::{Error, Result};
+use std::{borrow::Cow, path::Path};
+use xlsxwriter::{Format, Workbook, Worksheet};
+
+/// Name and headers of the worksheet listing the BOMs.
+const BOMS_SHEET: (&str, [&str; 2]) = ("BOMs", ["id", "name"]);
+
+/// Name and headers of the worksheet listing the entries of each BOM.
+const BOM_ENTRIES_SHEET: (&str, [&str; 4]) =
+    ("BOM Entries", ["bom_id", "entry_type", "entry_id", "quantity"]);
+
+/// Column widths are fitted to the longest value in the column, within these bounds.
+const MIN_COLUMN_WIDTH: usize = 8;
+const MAX_COLUMN_WIDTH: usize = 60;
+
+/// Cell content to write to a worksheet.
+enum Cell<'a> {
+    Text(Cow<'a, str>),
+    Number(f64),
+    Boolean(bool),
+    /// Cell left without a value.
+    Blank,
+}
+
+impl<'a> From<&'a Value<'a>> for Cell<'a> {
+    fn from(value: &'a Value<'a>) -> Self {
+        match value {
+            Value::Text(text) => Cell::Text(Cow::from(&**text)),
+            Value::Number(number) => Cell::Number(*number),
+            // Precision beyond 2^53 is kept by writing such integers as text.
+            Value::Integer(integer) if integer.unsigned_abs() <= 1 << 53 => {
+                Cell::Number(*integer as f64)
+            }
+            Value::Boolean(boolean) => Cell::Boolean(*boolean),
+            Value::Integer(_) | Value::Date(_) => Cell::Text(Cow::from(value.to_string())),
+            Value::Empty => Cell::Blank,
+        }
+    }
+}
+
+impl Cell<'_> {
+    /// Approximate width of the cell content in characters.
+    fn width(&self) -> usize {
+        match self {
+            Cell::Text(text) => text.chars().count(),
+            Cell::Number(number) => number.to_string().len(),
+            Cell::Boolean(boolean) => boolean.to_string().len(),
+            Cell::Blank => 0,
+        }
+    }
+}
+
+impl ItemSyncFormat<'_> {
+    /// Writes the item sync input workbook, with a "BOMs" and a "BOM Entries" worksheet, to the
+    /// path. Numeric ids, quantities and entry attributes are written as numeric cells.
+    pub fn write_xlsx(&self, path: &Path) -> Result<()> {
+        let workbook = Workbook::new(path_str(path)?)?;
+        let mut header_format = Format::new();
+        header_format.set_bold();
+
+        let boms = self
+            .boms
+            .iter()
+            .map(|bom| {
+                let mut row = vec![Cell::from(bom.id), Cell::from(bom.name)];
+                row.extend(bom.attributes.iter().copied().map(Cell::from));
+                row
+            })
+            .collect::<Vec<_>>();
+        let (name, headers) = BOMS_SHEET;
+        let headers = headers
+            .into_iter()
+            .chain(self.entry_keys.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        write_worksheet(&mut workbook.add_worksheet(Some(name))?, &headers, &boms, &header_format)?;
+
+        let bom_entries = self
+            .bom_entries
+            .iter()
+            .map(|entry| {
+                let mut row = vec![
+                    Cell::from(entry.bom_id),
+                    Cell::Text(Cow::from(entry.entry_type)),
+                    Cell::from(entry.entry_id),
+                    Cell::Number(entry.quantity),
+                ];
+                row.extend(entry.attributes.iter().copied().map(Cell::from));
+                row
+            })
+            .collect::<Vec<_>>();
+        let (name, headers) = BOM_ENTRIES_SHEET;
+        let headers = headers
+            .into_iter()
+            .chain(self.entry_keys.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        write_worksheet(
+            &mut workbook.add_worksheet(Some(name))?,
+            &headers,
+            &bom_entries,
+            &header_format,
+        )?;
+
+        workbook.close()?;
+        Ok(())
+    }
+}
+
+impl FlatData<'_> {
+    /// Writes the keys as the header row followed by the records to a single worksheet XLSX file.
+    pub fn write_xlsx(&self, path: &Path, sheet_name: &str) -> Result<()> {
+        let workbook = Workbook::new(path_str(path)?)?;
+        let mut header_format = Format::new();
+        header_format.set_bold();
+        let headers = self.keys.iter().map(|key| &**key).collect::<Vec<_>>();
+        let rows = self
+            .records
+            .iter()
+            .map(|record| record.iter().map(Cell::from).collect())
+            .collect::<Vec<_>>();
+        write_worksheet(
+            &mut workbook.add_worksheet(Some(sheet_name))?,
+            &headers,
+            &rows,
+            &header_format,
+        )?;
+        workbook.close()?;
+        Ok(())
+    }
+}
+
+/// Converts the path to the string xlsxwriter expects.
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| Error::invalid_argument(format!("Output path {path:?} is not valid UTF-8")))
+}
+
+/// Writes the bold headers followed by the rows, and fits the column widths to the content.
+fn write_worksheet(
+    worksheet: &mut Worksheet,
+    headers: &[&str],
+    rows: &[Vec<Cell>],
+    header_format: &Format,
+) -> Result<()> {
+    let mut widths = headers.iter().map(|header| header.chars().count()).collect::<Vec<_>>();
+    for (column, header) in headers.iter().enumerate() {
+        worksheet.write_string(0, column as u16, header, Some(header_format))?;
+    }
+    for (row_index, row) in rows.iter().enumerate() {
+        let row_index = u32::try_from(row_index + 1)
+            .map_err(|_| Error::invalid_argument("Too many rows for an XLSX worksheet"))?;
+        for (column, cell) in row.iter().enumerate() {
+            if let Some(width) = widths.get_mut(column) {
+                *width = (*width).max(cell.width());
+            }
+            match cell {
+                Cell::Text(text) => worksheet.write_string(row_index, column as u16, text, None)?,
+                Cell::Number(number) => {
+                    worksheet.write_number(row_index, column as u16, *number, None)?
+                }
+                Cell::Boolean(boolean) => {
+                    worksheet.write_boolean(row_index, column as u16, *boolean, None)?
+                }
+                Cell::Blank => {}
+            }
+        }
+    }
+    for (column, width) in widths.into_iter().enumerate() {
+        let width = width.clamp(MIN_COLUMN_WIDTH, MAX_COLUMN_WIDTH) as f64 + 2.0;
+        worksheet.set_column(column as u16, column as u16, width, None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        transform, ChildIdentificationPolicy, FlatData, HeaderRow, ItemSyncFormat,
+        ItemSyncFormatRules, LevelValidation, OutputRules, Rules, SheetSelection, ValueType,
+    };
+    use calamine::{open_workbook, Data, Reader, Xlsx};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn writes_item_sync_workbook() {
+        let rules = Rules {
+            type_mapping: Some([("Quantity".to_string(), ValueType::Number)].into_iter().collect()),
+            number_formats: None,
+            header_aliases: None,
+            header_row: HeaderRow::Detect,
+            drop_footer_rows: true,
+            sheet_selection: SheetSelection::First,
+            child_identification_policy: ChildIdentificationPolicy::OrderedLevelKey(
+                "level".to_string(),
+            ),
+            level_validation: LevelValidation::Strict,
+            consolidation: None,
+            rollups: Vec::new(),
+            output_rules: OutputRules::ItemSync(ItemSyncFormatRules {
+                id_key: "Part Number".to_string(),
+                name_key: Some("Part Name".to_string()),
+                quantity_key: Some("Quantity".to_string()),
+            }),
+        };
+        let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+        let data = include_bytes!("../../test_data/simple.xlsx");
+        let flat_data = FlatData::from_xlsx(data, &rules).unwrap();
+        let folded_data = transform(&flat_data, &rules).unwrap();
+        let formatted_data = ItemSyncFormat::format_item_sync(&folded_data, item_sync_rules);
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("item_sync.xlsx");
+        formatted_data.unwrap().write_xlsx(&path).unwrap();
+
+        let mut workbook: Xlsx<_> = open_workbook(&path).unwrap();
+        assert_eq!(workbook.sheet_names(), vec!["BOMs", "BOM Entries"]);
+        let boms = workbook.worksheet_range("BOMs").unwrap();
+        assert_eq!(
+            boms.rows().collect::<Vec<_>>(),
+            vec![
+                &[Data::String("id".to_string()), Data::String("name".to_string())][..],
+                &[Data::String("A-100".to_string()), Data::String("Assembly".to_string())],
+            ]
+        );
+        let bom_entries = workbook.worksheet_range("BOM Entries").unwrap();
+        let rows = bom_entries.rows().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[1],
+            &[
+                Data::String("A-100".to_string()),
+                Data::String("part".to_string()),
+                Data::Float(2001.0),
+                Data::Float(4.0),
+            ]
+        );
+    }
+}
@@ -1,57 +1,154 @@
 //! CSV parsing for the chaperone.
 
-use crate::transform::{FlatData, Rules, Value, ValueType};
+use crate::transform::{FlatData, HeaderRow, Rules, Value};
 use csv::StringRecord;
-use error::{Error, Result};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
 use std::{borrow::Cow, io::Cursor};
 
 impl FlatData<'_> {
     /// Creates `FlatData` from CSV content buffer.
     ///
+    /// The headers are read from the row selected by the `HeaderRow` in the `Rules`, and trailing
+    /// footer rows are dropped. Headers that match a key in the `Rules` or one of its aliases,
+    /// ignoring case, whitespace and punctuation, are renamed to that key.
+    ///
     /// Annoyingly, we have to clone the data because the CsvReader doesn't propagate lifetimes
     /// properly.
     pub fn from_csv<'a>(data: &'a [u8], rules: &Rules) -> Result<FlatData<'a>> {
+        // Preamble and footer rows usually have fewer fields than the records.
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(Cursor::new(data));
+        let mut rows = reader.records().collect::<std::result::Result<Vec<_>, _>>()?;
+        let header_row = match rules.header_row {
+            HeaderRow::Detect => super::detect_header_row(rows.iter(), rules),
+            // The index is the zero-based line, which differs from the position among the rows if
+            // there are blank lines or quoted line breaks above the headers.
+            HeaderRow::Index(index) => {
+                match rows.iter().position(|row| record_line(data, row) == index as u64 + 1) {
+                    Some(row) => row,
+                    None => return Err(header_line_error(data, index)),
+                }
+            }
+        };
+        if header_row >= rows.len() {
+            return super::finalize(FlatData::default(), rules);
+        }
+        let mut rows = rows.split_off(header_row);
+        let headers = super::canonicalize_headers(&rows.remove(0), rules)?;
+        let footer_rows = super::count_footer_rows(&rows, &headers, rules, |row, column| {
+            row.get(column).unwrap_or_default().trim().is_empty()
+        });
+        rows.truncate(rows.len() - footer_rows);
+
+        let mut field_violations = Vec::new();
+        let mut records = Vec::with_capacity(rows.len());
+        let mut sources = Vec::with_capacity(rows.len());
+        for record in rows {
+            let source = format!("line {}", record_line(data, &record));
+            if record.len() != headers.len() {
+                field_violations.push(FieldViolation {
+                    field: Some(source),
+                    description: Some(format!(
+                        "Record has {} field(s), but there are {} header(s)",
+                        record.len(),
+                        headers.len()
+                    )),
+                });
+                continue;
+            }
+            records.push(Self::make_flat_data_record(
+                &source,
+                record,
+                &headers,
+                rules,
+                &mut field_violations,
+            ));
+            sources.push(source);
+        }
+
+        if !field_violations.is_empty() {
+            return Err(Error::invalid_argument_with(
+                format!("Found {} invalid record(s) or value(s)", field_violations.len()),
+                Some(BadRequest { field_violations }),
+            ));
+        }
+        super::finalize(FlatData { keys: headers, records, sources, ..Default::default() }, rules)
+    }
+
+    /// Creates `FlatData` from a CSV table with the headers on the first line, keeping the headers
+    /// and values as is. Used for tables written by this crate rather than arbitrary input, so no
+    /// `Rules` are needed.
+    pub(crate) fn from_csv_table(data: &[u8]) -> Result<FlatData<'static>> {
         let mut reader = csv::Reader::from_reader(Cursor::new(data));
-        let headers =
-            reader.headers()?.iter().map(ToString::to_string).map(Cow::from).collect::<Vec<_>>();
-        let records = reader
-            .records()
-            .map(|record| Self::make_flat_data_record(record?, &headers, rules))
-            .collect::<Result<Vec<_>>>()?;
-        Ok(FlatData { keys: headers, records })
+        let keys = reader.headers()?.iter().map(|header| Cow::Owned(header.to_string())).collect();
+        let mut records = Vec::new();
+        let mut sources = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            sources.push(format!("line {}", record_line(data, &record)));
+            records.push(record.iter().map(Value::text_owned).collect());
+        }
+        Ok(FlatData { keys, records, sources, ..Default::default() })
     }
 
     /// Converts the typeless CSV record into semi-typed `FlatData` according ot the type
-    /// mapping in the `Rules`.
+    /// mapping and number formats in the `Rules`. Values that can't be parsed are left empty, and
+    /// a violation naming the source line of the record and the header is added for each.
     fn make_flat_data_record(
+        source: &str,
         record: StringRecord,
         headers: &[Cow<'_, str>],
         rules: &Rules,
-    ) -> Result<Vec<Value<'static>>> {
+        field_violations: &mut Vec<FieldViolation>,
+    ) -> Vec<Value<'static>> {
         record
             .into_iter()
-            .enumerate()
-            .map(|(index, value)| {
-                let maybe_header = headers.get(index);
-                let value_type = maybe_header
-                    .zip(rules.type_mapping.as_ref())
-                    .and_then(|(key, map)| map.get(&**key));
-                Ok(match value_type {
-                    Some(ValueType::Number) => {
-                        let value = if value.is_empty() {
-                            0.0
-                        } else {
-                            value.parse::<f64>().map_err(|e| {
-                                Error::invalid_argument(format!(
-                                    "Failed to parse record as number for {maybe_header:?} -> {value:?}: {e:?}"
-                                ))
-                            })?
-                        };
-                        Value::Number(value)
-                    }
-                    _ => Value::text_owned(value),
-                })
+            .zip(headers)
+            .map(|(value, key)| match rules.value_type(key) {
+                Some(value_type) => value_type
+                    .parse(value, rules.number_format(key))
+                    .unwrap_or_else(|description| {
+                        field_violations.push(FieldViolation {
+                            field: Some(source.to_string()),
+                            description: Some(format!("{key:?}: {description}")),
+                        });
+                        Value::Empty
+                    }),
+                None => Value::text_owned(value),
             })
             .collect()
     }
 }
+
+/// One-based line of the input the record starts on. The reader skips blank lines, but reports the
+/// position of a record that follows some as that of the first of them.
+fn record_line(data: &[u8], record: &StringRecord) -> u64 {
+    let Some(position) = record.position() else { return 0 };
+    let skipped = data
+        .get(position.byte() as usize..)
+        .unwrap_or_default()
+        .iter()
+        .take_while(|byte| matches!(byte, b'\r' | b'\n'))
+        .filter(|byte| **byte == b'\n')
+        .count();
+    position.line() + skipped as u64
+}
+
+/// Explains why the zero-based line can't be the header row: it's past the end of the input, or
+/// it's blank or continues a quoted field of the row above.
+fn header_line_error(data: &[u8], index: usize) -> Error {
+    let line_count = data.iter().filter(|byte| **byte == b'\n').count()
+        + usize::from(!data.is_empty() && !data.ends_with(b"\n"));
+    if index >= line_count {
+        return error::out_of_range!(format!("Input only has {line_count} line(s)"), index);
+    }
+    Error::invalid_argument(format!(
+        "Line {} is blank or continues a quoted field, so it can't be the header row",
+        index + 1
+    ))
+}
@@ -0,0 +1,362 @@
+//! Parsing of the item sync input files written by `ItemSyncFormat` back into a hierarchy.
+
+use crate::transform::{
+    find_key, FlatData, ItemSyncFormatRules, OwnedFoldedData, OwnedNode, Value, ValueType,
+};
+use error::{
+    error_details::{precondition_failure, PreconditionFailure},
+    Error, Result,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+/// Worksheet names written by `ItemSyncFormat`.
+const BOMS_SHEET: &str = "BOMs";
+const BOM_ENTRIES_SHEET: &str = "BOM Entries";
+
+impl OwnedFoldedData {
+    /// Rebuilds the hierarchy from the contents of the "boms.csv" and "bom_entries.csv" files
+    /// written by [`ItemSyncFormat::write_csv`](crate::ItemSyncFormat::write_csv).
+    ///
+    /// See [`Self::from_item_sync_xlsx`] for how the hierarchy is rebuilt.
+    pub fn from_item_sync_csv(
+        boms: &[u8],
+        bom_entries: &[u8],
+        rules: &ItemSyncFormatRules,
+    ) -> Result<OwnedFoldedData> {
+        let boms = FlatData::from_csv_table(boms)?;
+        let bom_entries = FlatData::from_csv_table(bom_entries)?;
+        ItemSyncTables::new(&boms, &bom_entries)?.fold(rules)
+    }
+
+    /// Rebuilds the hierarchy from the "BOMs" and "BOM Entries" worksheets of the workbook written
+    /// by [`ItemSyncFormat::write_xlsx`](crate::ItemSyncFormat::write_xlsx).
+    ///
+    /// BOMs that aren't an entry of another BOM are the top level items, and the entries of each
+    /// "sub-bom" entry are expanded recursively. The attributes are named after the keys in the
+    /// `rules`: each item has its id, its name (empty for parts, since only BOMs are named) and its
+    /// quantity in the parent BOM (1 for top level items).
+    pub fn from_item_sync_xlsx(
+        data: &[u8],
+        rules: &ItemSyncFormatRules,
+    ) -> Result<OwnedFoldedData> {
+        let boms = FlatData::from_xlsx_table(data, BOMS_SHEET)?;
+        let bom_entries = FlatData::from_xlsx_table(data, BOM_ENTRIES_SHEET)?;
+        ItemSyncTables::new(&boms, &bom_entries)?.fold(rules)
+    }
+}
+
+/// Single row of the "BOM Entries" table.
+struct BomEntry<'a> {
+    entry_type: String,
+    entry_id: &'a Value<'a>,
+    quantity: f64,
+}
+
+/// The item sync tables, indexed by BOM id.
+struct ItemSyncTables<'a> {
+    /// Ids of the BOMs in order of first appearance in either table.
+    bom_ids: Vec<&'a Value<'a>>,
+    names: HashMap<String, &'a Value<'a>>,
+    entries: HashMap<String, Vec<BomEntry<'a>>>,
+}
+
+impl<'a> ItemSyncTables<'a> {
+    fn new(boms: &'a FlatData<'a>, bom_entries: &'a FlatData<'a>) -> Result<Self> {
+        let boms_column = |key| find_key(&boms.keys, key, &format!("{BOMS_SHEET:?} column"));
+        let entries_column =
+            |key| find_key(&bom_entries.keys, key, &format!("{BOM_ENTRIES_SHEET:?} column"));
+        let (id, name) = (boms_column("id")?, boms_column("name")?);
+        let bom_id = entries_column("bom_id")?;
+        let entry_type = entries_column("entry_type")?;
+        let entry_id = entries_column("entry_id")?;
+        let quantity = entries_column("quantity")?;
+
+        let mut tables =
+            ItemSyncTables { bom_ids: Vec::new(), names: HashMap::new(), entries: HashMap::new() };
+        for record in boms.records.iter() {
+            tables.add_bom(&record[id]);
+            tables.names.insert(record[id].to_string(), &record[name]);
+        }
+        for (index, record) in bom_entries.records.iter().enumerate() {
+            // Quantities are text when read from CSV.
+            let entry_quantity = match record[quantity] {
+                Value::Text(ref text) => {
+                    ValueType::Number.parse(text, None).map_err(|description| {
+                        Error::invalid_argument(format!(
+                            "{}: \"quantity\": {description}",
+                            bom_entries.source(index)
+                        ))
+                    })?
+                }
+                ref value => value.clone(),
+            };
+            tables.add_bom(&record[bom_id]);
+            tables.entries.entry(record[bom_id].to_string()).or_default().push(BomEntry {
+                entry_type: record[entry_type].to_string(),
+                entry_id: &record[entry_id],
+                quantity: entry_quantity.as_f64().unwrap_or(1.0),
+            });
+        }
+        Ok(tables)
+    }
+
+    fn add_bom(&mut self, id: &'a Value<'a>) {
+        let key = id.to_string();
+        if !self.names.contains_key(&key) && !self.entries.contains_key(&key) {
+            self.bom_ids.push(id);
+        }
+    }
+
+    /// Builds the hierarchy, with the BOMs that aren't entries of other BOMs at the top level.
+    fn fold(&self, rules: &ItemSyncFormatRules) -> Result<OwnedFoldedData> {
+        let mut attribute_keys = vec![Cow::from(rules.id_key.clone())];
+        attribute_keys.extend(rules.name_key.clone().map(Cow::from));
+        attribute_keys.extend(rules.quantity_key.clone().map(Cow::from));
+
+        let entry_ids = self
+            .entries
+            .values()
+            .flatten()
+            .map(|entry| entry.entry_id.to_string())
+            .collect::<HashSet<_>>();
+        let mut builder = Builder {
+            tables: self,
+            rules,
+            path: Vec::new(),
+            expanded: HashSet::new(),
+            violations: Vec::new(),
+        };
+        let top_level_nodes = self
+            .bom_ids
+            .iter()
+            .filter(|id| !entry_ids.contains(&id.to_string()))
+            .map(|id| builder.node(id, "sub-bom", 1.0))
+            .collect::<Vec<_>>();
+        // BOMs that are only entries of each other can't be reached from a top level BOM. They're
+        // expanded anyway to report the cycles they form.
+        let unreachable = self
+            .bom_ids
+            .iter()
+            .filter(|id| !builder.expanded.contains(&id.to_string()))
+            .collect::<Vec<_>>();
+        for id in unreachable.iter() {
+            if !builder.expanded.contains(&id.to_string()) {
+                builder.node(id, "sub-bom", 1.0);
+            }
+        }
+        for id in unreachable.into_iter().map(ToString::to_string) {
+            builder.violations.push(precondition_failure::Violation {
+                r#type: Some("UNREACHABLE".to_string()),
+                subject: Some(id),
+                description: Some(
+                    "BOM is only an entry of BOMs that form a cycle, so it has no top level BOM"
+                        .to_string(),
+                ),
+            });
+        }
+        if !builder.violations.is_empty() {
+            return Err(Error::failed_precondition_with(
+                format!("Found {} problem(s) in the BOM entries", builder.violations.len()),
+                Some(PreconditionFailure { violations: builder.violations }),
+            ));
+        }
+        Ok(OwnedFoldedData { attribute_keys, top_level_nodes })
+    }
+}
+
+/// Expands the BOMs depth first, keeping track of the BOMs being expanded to detect cycles.
+struct Builder<'a, 'r> {
+    tables: &'r ItemSyncTables<'a>,
+    rules: &'r ItemSyncFormatRules,
+    path: Vec<String>,
+    expanded: HashSet<String>,
+    violations: Vec<precondition_failure::Violation>,
+}
+
+impl Builder<'_, '_> {
+    fn node(&mut self, id: &Value, entry_type: &str, quantity: f64) -> OwnedNode {
+        let key = id.to_string();
+        let mut attributes = vec![id.to_owned_value()];
+        if self.rules.name_key.is_some() {
+            let name = self.tables.names.get(&key).map(|name| name.to_owned_value());
+            attributes.push(name.unwrap_or_else(|| Value::text_owned("")));
+        }
+        if self.rules.quantity_key.is_some() {
+            attributes.push(Value::Number(quantity));
+        }
+        if entry_type != "sub-bom" {
+            return OwnedNode { attributes, children: Vec::new() };
+        }
+
+        if self.path.contains(&key) {
+            let mut cycle = self.path.clone();
+            cycle.push(key);
+            self.violations.push(precondition_failure::Violation {
+                r#type: Some("CYCLE".to_string()),
+                subject: Some(cycle.join(" -> ")),
+                description: Some("Sub-BOM entries form a cycle".to_string()),
+            });
+            return OwnedNode { attributes, children: Vec::new() };
+        }
+        let Some(entries) = self.tables.entries.get(&key) else {
+            self.violations.push(precondition_failure::Violation {
+                r#type: Some("MISSING_SUB_BOM".to_string()),
+                subject: Some(key),
+                description: Some("Sub-BOM has no BOM entries".to_string()),
+            });
+            return OwnedNode { attributes, children: Vec::new() };
+        };
+        self.expanded.insert(key.clone());
+        self.path.push(key);
+        let children = entries
+            .iter()
+            .map(|entry| self.node(entry.entry_id, &entry.entry_type, entry.quantity))
+            .collect();
+        self.path.pop();
+        OwnedNode { attributes, children }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        transform, ChildIdentificationPolicy, FlatData, HeaderRow, ItemSyncFormat,
+        ItemSyncFormatRules, LevelValidation, OutputRules, OwnedFoldedData, Rules, SheetSelection,
+        ValueType,
+    };
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    fn rules() -> Rules {
+        Rules {
+            type_mapping: Some([("Quantity".to_string(), ValueType::Number)].into_iter().collect()),
+            number_formats: None,
+            header_aliases: None,
+            header_row: HeaderRow::Detect,
+            drop_footer_rows: true,
+            sheet_selection: SheetSelection::All,
+            child_identification_policy: ChildIdentificationPolicy::OrderedLevelKey(
+                "level".to_string(),
+            ),
+            level_validation: LevelValidation::Strict,
+            consolidation: None,
+            rollups: Vec::new(),
+            output_rules: OutputRules::ItemSync(ItemSyncFormatRules {
+                id_key: "Part Number".to_string(),
+                name_key: Some("Part Name".to_string()),
+                quantity_key: Some("Quantity".to_string()),
+            }),
+        }
+    }
+
+    /// Renders each item as "<depth> <id> <name> x<quantity>".
+    fn lines(owned: &OwnedFoldedData) -> Vec<String> {
+        let folded_data = owned.folded_data();
+        let mut lines = Vec::new();
+        let mut stack =
+            folded_data.top_level_nodes.iter().rev().map(|node| (node, 0)).collect::<Vec<_>>();
+        while let Some((node, depth)) = stack.pop() {
+            let [id, name, quantity] = node.attributes else { panic!("{:?}", node.attributes) };
+            lines.push(format!("{depth} {id} {name} x{quantity}"));
+            stack.extend(node.children.iter().rev().map(|child| (child, depth + 1)));
+        }
+        lines
+    }
+
+    #[test]
+    fn reads_written_item_sync_files() {
+        let rules = rules();
+        let OutputRules::ItemSync(ref item_sync_rules) = rules.output_rules;
+        let data = include_bytes!("../../test_data/assemblies.xlsx");
+        let flat_data = FlatData::from_xlsx(data, &rules).unwrap();
+        let folded_data = transform(&flat_data, &rules).unwrap();
+        let formatted_data =
+            ItemSyncFormat::format_item_sync(&folded_data, item_sync_rules).unwrap();
+
+        let directory = tempfile::tempdir().unwrap();
+        formatted_data.write_csv(directory.path()).unwrap();
+        let path = directory.path().join("item_sync.xlsx");
+        formatted_data.write_xlsx(&path).unwrap();
+
+        let boms = std::fs::read(directory.path().join("boms.csv")).unwrap();
+        let bom_entries = std::fs::read(directory.path().join("bom_entries.csv")).unwrap();
+        let from_csv =
+            OwnedFoldedData::from_item_sync_csv(&boms, &bom_entries, item_sync_rules).unwrap();
+        let from_xlsx =
+            OwnedFoldedData::from_item_sync_xlsx(&std::fs::read(path).unwrap(), item_sync_rules)
+                .unwrap();
+        assert_eq!(lines(&from_csv), lines(&from_xlsx));
+        assert_eq!(from_csv.attribute_keys, ["Part Number", "Part Name", "Quantity"]);
+        assert_eq!(lines(&from_csv).len(), {
+            let mut count = 0;
+            let mut stack = folded_data.top_level_nodes.iter().collect::<Vec<_>>();
+            while let Some(node) = stack.pop() {
+                count += 1;
+                stack.extend(node.children.iter());
+            }
+            count
+        });
+    }
+
+    #[test]
+    fn expands_shared_sub_boms() {
+        let boms = "id,name\nA,Assembly\nS,Subassembly\n";
+        let bom_entries = "bom_id,entry_type,entry_id,quantity\n\
+                           A,sub-bom,S,2\n\
+                           A,part,P1,1\n\
+                           S,part,P2,4\n\
+                           B,sub-bom,S,1\n";
+        let OutputRules::ItemSync(ref item_sync_rules) = rules().output_rules;
+        let owned = OwnedFoldedData::from_item_sync_csv(
+            boms.as_bytes(),
+            bom_entries.as_bytes(),
+            item_sync_rules,
+        )
+        .unwrap();
+        assert_eq!(
+            lines(&owned),
+            [
+                "0 A Assembly x1",
+                "1 S Subassembly x2",
+                "2 P2  x4",
+                "1 P1  x1",
+                "0 B  x1",
+                "1 S Subassembly x1",
+                "2 P2  x4",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let boms = "id,name\nA,Assembly\nS,Subassembly\n";
+        let bom_entries = "bom_id,entry_type,entry_id,quantity\n\
+                           A,sub-bom,S,1\n\
+                           S,sub-bom,A,1\n";
+        let OutputRules::ItemSync(ref item_sync_rules) = rules().output_rules;
+        let result = OwnedFoldedData::from_item_sync_csv(
+            boms.as_bytes(),
+            bom_entries.as_bytes(),
+            item_sync_rules,
+        );
+        let violations = match result {
+            Err(Error::FailedPrecondition(payload)) => payload.payload.unwrap().violations,
+            other => panic!("Expected failed precondition, got {other:?}"),
+        };
+        let violations = violations
+            .into_iter()
+            .map(|v| (v.r#type.unwrap(), v.subject.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            violations,
+            [
+                ("CYCLE".to_string(), "A -> S -> A".to_string()),
+                ("UNREACHABLE".to_string(), "A".to_string()),
+                ("UNREACHABLE".to_string(), "S".to_string()),
+            ]
+        );
+    }
+}
@@ -1,2 +1,363 @@
 mod csv;
+mod item_sync;
+mod rules;
 mod xlsx;
+
+use crate::transform::{ChildIdentificationPolicy, FlatData, Rules};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+/// Maximum number of rows searched by `HeaderRow::Detect`.
+const HEADER_SEARCH_ROWS: usize = 100;
+
+/// Finds the first row that contains all of the keys required by the `Rules`, or one of their
+/// aliases, falling back to the first row.
+fn detect_header_row<I, R, S>(rows: I, rules: &Rules) -> usize
+where
+    I: IntoIterator<Item = R>,
+    R: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let required_keys = rules.required_keys();
+    rows.into_iter()
+        .take(HEADER_SEARCH_ROWS)
+        .position(|row| {
+            let keys = row
+                .into_iter()
+                .filter_map(|cell| {
+                    rules.matching_key(cell.as_ref()).or_else(|| rules.aliased_key(cell.as_ref()))
+                })
+                .collect::<Vec<_>>();
+            required_keys.iter().all(|key| keys.contains(key))
+        })
+        .unwrap_or(0)
+}
+
+/// Counts the trailing rows that are blank in the column of any of the `item_keys` of the
+/// `Rules`, like a totals row or an export footer, unless disabled by the `Rules`.
+fn count_footer_rows<R, F>(rows: &[R], headers: &[Cow<str>], rules: &Rules, is_blank: F) -> usize
+where
+    F: Fn(&R, usize) -> bool,
+{
+    if !rules.drop_footer_rows {
+        return 0;
+    }
+    let item_columns = rules
+        .item_keys()
+        .into_iter()
+        .map(|key| headers.iter().position(|header| header == key))
+        .collect::<Option<Vec<_>>>();
+    // If a key is missing altogether, the error is reported when the key is looked up.
+    let Some(item_columns) = item_columns else { return 0 };
+    let count = rows
+        .iter()
+        .rev()
+        .take_while(|row| item_columns.iter().any(|column| is_blank(row, *column)))
+        .count();
+    if count > 0 {
+        tracing::info!("Dropped {count} footer row(s) missing one of {:?}", rules.item_keys());
+    }
+    count
+}
+
+/// Renames the headers that stand for a key in the `Rules` to that key, so that the keys can be
+/// looked up exactly from then on. Headers matching a key directly take precedence over headers
+/// matching one of its aliases, e.g. "Description" is left alone if it's an alias of "Part Name"
+/// and there's also a "Part Name" header. Other headers are kept as is.
+///
+/// Fails if several headers stand for the same key with the same precedence, e.g. both
+/// "Part Number" and "part_number".
+fn canonicalize_headers<I, S>(headers: I, rules: &Rules) -> Result<Vec<Cow<'static, str>>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let headers = headers.into_iter().map(|header| header.as_ref().to_string()).collect::<Vec<_>>();
+    let matches = headers
+        .iter()
+        .map(|header| {
+            let direct_match = rules.matching_key(header).map(|key| (key, true));
+            direct_match.or_else(|| rules.aliased_key(header).map(|key| (key, false)))
+        })
+        .collect::<Vec<_>>();
+    let directly_matched_keys = matches
+        .iter()
+        .flatten()
+        .filter_map(|(key, direct)| direct.then_some(*key))
+        .collect::<HashSet<_>>();
+    let keys = matches
+        .into_iter()
+        .map(|matched| {
+            let (key, direct) = matched?;
+            (direct || !directly_matched_keys.contains(key)).then_some(key)
+        })
+        .collect::<Vec<_>>();
+
+    let mut matched_headers: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (header, key) in headers.iter().zip(keys.iter()) {
+        if let Some(key) = key {
+            matched_headers.entry(key).or_default().push(header);
+        }
+    }
+    let mut field_violations = matched_headers
+        .into_iter()
+        .filter(|(_, headers)| headers.len() > 1)
+        .map(|(key, headers)| FieldViolation {
+            field: Some(key.to_string()),
+            description: Some(format!("Key is matched by several headers: {headers:?}")),
+        })
+        .collect::<Vec<_>>();
+    if !field_violations.is_empty() {
+        field_violations.sort_unstable_by(|lhs, rhs| lhs.field.cmp(&rhs.field));
+        return Err(Error::invalid_argument_with(
+            format!("Found {} key(s) matched by several headers", field_violations.len()),
+            Some(BadRequest { field_violations }),
+        ));
+    }
+    Ok(headers
+        .into_iter()
+        .zip(keys)
+        .map(|(header, key)| Cow::from(key.map(ToString::to_string).unwrap_or(header)))
+        .collect())
+}
+
+/// Post-processing shared by all of the parsers once the records have been read.
+fn finalize<'a>(mut flat_data: FlatData<'a>, rules: &Rules) -> Result<FlatData<'a>> {
+    if let ChildIdentificationPolicy::IndentedKey(ref indentation) =
+        rules.child_identification_policy
+    {
+        flat_data.extract_indentation(indentation)?;
+    }
+    Ok(flat_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::{
+        ChildIdentificationPolicy, FlatData, HeaderRow, ItemSyncFormatRules, LevelValidation,
+        NumberFormat, OutputRules, Rules, SheetSelection, Value, ValueType,
+    };
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    fn rules() -> Rules {
+        Rules {
+            type_mapping: Some([("Quantity".to_string(), ValueType::Number)].into_iter().collect()),
+            number_formats: None,
+            header_aliases: Some(
+                [
+                    ("Part Number".to_string(), vec!["PN".to_string(), "Part No.".to_string()]),
+                    ("Part Name".to_string(), vec!["Description".to_string()]),
+                    ("Quantity".to_string(), vec!["Qty".to_string()]),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            header_row: HeaderRow::Detect,
+            drop_footer_rows: true,
+            sheet_selection: SheetSelection::First,
+            child_identification_policy: ChildIdentificationPolicy::OrderedLevelKey(
+                "level".to_string(),
+            ),
+            level_validation: LevelValidation::Lenient,
+            consolidation: None,
+            rollups: Vec::new(),
+            output_rules: OutputRules::ItemSync(ItemSyncFormatRules {
+                id_key: "Part Number".to_string(),
+                name_key: Some("Part Name".to_string()),
+                quantity_key: Some("Quantity".to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn canonicalizes_headers() {
+        let data = b"Part No.,DESCRIPTION,qty ,Level,Notes\nA-100,Assembly,1,1,\n";
+        let flat_data = FlatData::from_csv(data, &rules()).unwrap();
+        assert_eq!(flat_data.keys, vec!["Part Number", "Part Name", "Quantity", "level", "Notes"]);
+        assert_eq!(flat_data.records[0][2], Value::Number(1.0));
+    }
+
+    #[test]
+    fn prefers_direct_matches_over_aliases() {
+        let data = b"PN,part_name,Description,Quantity,level\n";
+        let flat_data = FlatData::from_csv(data, &rules()).unwrap();
+        assert_eq!(
+            flat_data.keys,
+            vec!["Part Number", "Part Name", "Description", "Quantity", "level"]
+        );
+    }
+
+    #[test]
+    fn rejects_ambiguous_headers() {
+        let data = b"Part Number,PART_NUMBER,PN,Qty,Quantity,level\n";
+        let payload = match FlatData::from_csv(data, &rules()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        let fields = payload
+            .field_violations
+            .into_iter()
+            .map(|violation| violation.field.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["Part Number"]);
+    }
+
+    #[test]
+    fn skips_preamble_and_footer_rows() {
+        let data = b"Widget BOM export\nExported by jdoe on 2022-10-01\n\n\
+            Part Number,Part Name,Quantity,level\nA-100,Assembly,1,1\nB-200,Bolt,4,2\n,Total,5,\n";
+        let flat_data = FlatData::from_csv(data, &rules()).unwrap();
+        assert_eq!(flat_data.keys, vec!["Part Number", "Part Name", "Quantity", "level"]);
+        assert_eq!(flat_data.records.len(), 2);
+        assert_eq!(flat_data.records[1][1], Value::text("Bolt"));
+        assert_eq!(flat_data.sources, ["line 5", "line 6"]);
+
+        // The index is the zero-based line, blank lines included.
+        let fixed_row =
+            Rules { header_row: HeaderRow::Index(3), drop_footer_rows: false, ..rules() };
+        let flat_data = FlatData::from_csv(data, &fixed_row).unwrap();
+        assert_eq!(flat_data.records.len(), 3);
+        assert_eq!(flat_data.records[2][1], Value::text("Total"));
+
+        let blank_line = Rules { header_row: HeaderRow::Index(2), ..rules() };
+        assert!(matches!(FlatData::from_csv(data, &blank_line), Err(Error::InvalidArgument(_))));
+        let past_end = Rules { header_row: HeaderRow::Index(7), ..rules() };
+        assert!(matches!(FlatData::from_csv(data, &past_end), Err(Error::OutOfRange(_))));
+    }
+
+    #[test]
+    fn parses_typed_values() {
+        let type_mapping = [
+            ("Quantity", ValueType::Integer),
+            ("Unit Cost", ValueType::Number),
+            ("Obsolete", ValueType::Boolean),
+            ("Released", ValueType::Date),
+        ]
+        .into_iter()
+        .map(|(key, value_type)| (key.to_string(), value_type))
+        .collect();
+        let rules = Rules { type_mapping: Some(type_mapping), ..rules() };
+        let data = b"Part Number,Quantity,Unit Cost,Obsolete,Released,level\n\
+            A-100,2,1.5,yes,2022-10-01,1\nB-200, ,,N,2022-10-01T08:30:00,2\n";
+        let flat_data = FlatData::from_csv(data, &rules).unwrap();
+        assert_eq!(flat_data.records[0][0], Value::text("A-100"));
+        assert_eq!(
+            flat_data.records[0][1..5],
+            [
+                Value::Integer(2),
+                Value::Number(1.5),
+                Value::Boolean(true),
+                Value::Date(chrono::NaiveDate::from_ymd_opt(2022, 10, 1).unwrap())
+            ]
+        );
+        assert_eq!(
+            flat_data.records[1][1..5],
+            [
+                Value::Empty,
+                Value::Empty,
+                Value::Boolean(false),
+                Value::Date(chrono::NaiveDate::from_ymd_opt(2022, 10, 1).unwrap())
+            ]
+        );
+
+        let data = b"Part Number,Quantity,level
+A-100,1.5,1
+";
+        assert!(matches!(FlatData::from_csv(data, &rules), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn parses_number_formats() {
+        let european = NumberFormat {
+            decimal_separator: ',',
+            thousands_separator: Some('.'),
+            suffixes: vec!["pcs".to_string()],
+        };
+        let percent = NumberFormat {
+            thousands_separator: Some(' '),
+            suffixes: vec!["%".to_string()],
+            ..Default::default()
+        };
+        let number_formats = [("Quantity", european), ("Scrap", percent)]
+            .into_iter()
+            .map(|(key, number_format)| (key.to_string(), number_format))
+            .collect();
+        let rules = Rules { number_formats: Some(number_formats), ..rules() };
+        let data = "Part Number,Quantity,Scrap,level\nA-100,\"1.000,5\",2 %,1\n\
+            B-200,4 PCS,1\u{a0}000,2\nC-300,\"2,5\",,2\n";
+        let flat_data = FlatData::from_csv(data.as_bytes(), &rules).unwrap();
+        let values = flat_data.records.iter().map(|record| &record[1..3]).collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            [
+                [Value::Number(1000.5), Value::Number(2.0)],
+                [Value::Number(4.0), Value::Number(1000.0)],
+                [Value::Number(2.5), Value::Empty]
+            ]
+        );
+
+        let data = b"Exported BOM\n\nPart Number,Quantity,Scrap,level\n\
+            A-100,1.5,2,1\nB-200,4,\"1,5\",2\nC-300,1234.567,,2\n";
+        let field_violations = match FlatData::from_csv(data, &rules) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        let violations = field_violations
+            .into_iter()
+            .map(|violation| (violation.field.unwrap(), violation.description.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            violations,
+            [
+                (
+                    "line 4".to_string(),
+                    "\"Quantity\": \"1.5\" has a misplaced thousands separator".to_string()
+                ),
+                (
+                    "line 5".to_string(),
+                    "\"Scrap\": \"1,5\" has an unexpected ',', expected '.' as the decimal \
+                     separator"
+                        .to_string()
+                ),
+                (
+                    "line 6".to_string(),
+                    "\"Quantity\": \"1234.567\" has a misplaced thousands separator".to_string()
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_source_lines() {
+        let data = b"Widget BOM export\n\nPart Number,Part Name,Quantity,level\n\
+            A-100,Assembly,1,1\n\nB-200,Bolt,4,3\n";
+        let rules = Rules { level_validation: LevelValidation::Strict, ..rules() };
+        let flat_data = FlatData::from_csv(data, &rules).unwrap();
+        let payload = match crate::transform(&flat_data, &rules) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(payload.field_violations[0].field.as_deref(), Some("line 6"));
+    }
+
+    #[test]
+    fn rejects_ragged_records() {
+        let data = b"Part Number,Part Name,Quantity,level\nA-100,Assembly,1\n\nB-200,Bolt,x,2\n";
+        let field_violations = match FlatData::from_csv(data, &rules()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap().field_violations,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        let fields = field_violations
+            .into_iter()
+            .map(|violation| violation.field.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(fields, ["line 2", "line 4"]);
+    }
+}
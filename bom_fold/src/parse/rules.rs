@@ -0,0 +1,255 @@
+//! Parsing of `Rules` from JSON, TOML and YAML configuration files.
+
+use crate::transform::Rules;
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use serde::Deserialize;
+use serde_path_to_error::Track;
+use serde_yaml::with::singleton_map_recursive;
+use std::{fmt::Display, io, path::Path};
+
+impl Rules {
+    /// Reads and validates rules from a file, choosing the format from its extension (`.json`,
+    /// `.toml`, `.yaml` or `.yml`).
+    pub fn from_path(path: &Path) -> Result<Rules> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            let message = format!("Couldn't read rules file {}: {e}", path.display());
+            Error::from(io::Error::new(e.kind(), message))
+        })?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&contents),
+            Some("toml") => Self::from_toml(&contents),
+            Some("yaml") | Some("yml") => Self::from_yaml(&contents),
+            _ => Err(Error::invalid_argument(
+                "Unrecognized rules file type. Please ensure your file has a .json, .toml, .yaml or \
+                 .yml extension",
+            )),
+        }
+    }
+
+    /// Reads and validates rules from JSON.
+    pub fn from_json(contents: &str) -> Result<Rules> {
+        Self::deserialize_validated(|track| {
+            let mut deserializer = serde_json::Deserializer::from_str(contents);
+            Rules::deserialize(serde_path_to_error::Deserializer::new(&mut deserializer, track))
+        })
+    }
+
+    /// Reads and validates rules from TOML.
+    pub fn from_toml(contents: &str) -> Result<Rules> {
+        Self::deserialize_validated(|track| {
+            let mut deserializer = toml::Deserializer::new(contents);
+            Rules::deserialize(serde_path_to_error::Deserializer::new(&mut deserializer, track))
+        })
+    }
+
+    /// Reads and validates rules from YAML.
+    ///
+    /// Enums may be written as single entry maps (`Name: Indented BOM`) like in the other formats,
+    /// as well as with YAML tags (`!Name Indented BOM`).
+    pub fn from_yaml(contents: &str) -> Result<Rules> {
+        Self::deserialize_validated(|track| {
+            let deserializer = serde_yaml::Deserializer::from_str(contents);
+            singleton_map_recursive::deserialize(serde_path_to_error::Deserializer::new(
+                deserializer,
+                track,
+            ))
+        })
+    }
+
+    /// Deserializes the rules, tracking the path to the field that failed if any, and then
+    /// validates them.
+    fn deserialize_validated<F, E>(deserialize: F) -> Result<Rules>
+    where
+        F: FnOnce(&mut Track) -> std::result::Result<Rules, E>,
+        E: Display,
+    {
+        let mut track = Track::new();
+        let rules = deserialize(&mut track).map_err(|e| {
+            let field = track.path().to_string();
+            let description = e.to_string();
+            Error::invalid_argument_with(
+                format!("Invalid rules at {field}: {description}"),
+                Some(BadRequest {
+                    field_violations: vec![FieldViolation {
+                        field: Some(field),
+                        description: Some(description),
+                    }],
+                }),
+            )
+        })?;
+        rules.validate()?;
+        Ok(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::{
+        ChildIdentificationPolicy, LevelValidation, OutputRules, Rules, SheetSelection, ValueType,
+    };
+    use error::Error;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    fn assert_example_rules(rules: Rules) {
+        assert_eq!(
+            rules.type_mapping,
+            Some([("Quantity".to_string(), ValueType::Number)].into_iter().collect())
+        );
+        assert_eq!(rules.sheet_selection, SheetSelection::Name("Indented BOM".to_string()));
+        assert_eq!(rules.level_validation, LevelValidation::Strict);
+        assert!(matches!(
+            rules.child_identification_policy,
+            ChildIdentificationPolicy::OrderedLevelKey(ref key) if key == "level"
+        ));
+        let OutputRules::ItemSync(item_sync) = rules.output_rules;
+        assert_eq!(item_sync.id_key, "Part Number");
+        assert_eq!(item_sync.name_key.as_deref(), Some("Part Name"));
+        assert_eq!(item_sync.quantity_key, None);
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("missing.json");
+        assert!(matches!(Rules::from_path(&path), Err(Error::NotFound(_))));
+
+        // Directories can be found, but not read.
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        match Rules::from_path(&path) {
+            Err(error @ Error::Internal(_)) => assert!(error.message().contains("src")),
+            other => panic!("Expected internal error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn json() {
+        let rules = Rules::from_json(
+            r#"{
+                "type_mapping": { "Quantity": "Number" },
+                "sheet_selection": { "Name": "Indented BOM" },
+                "child_identification_policy": { "OrderedLevelKey": "level" },
+                "level_validation": "Strict",
+                "output_rules": { "ItemSync": { "id_key": "Part Number", "name_key": "Part Name" } }
+            }"#,
+        );
+        assert_example_rules(rules.unwrap());
+    }
+
+    #[test]
+    fn toml() {
+        let rules = Rules::from_toml(
+            r#"
+            sheet_selection = { Name = "Indented BOM" }
+            child_identification_policy = { OrderedLevelKey = "level" }
+            level_validation = "Strict"
+            output_rules = { ItemSync = { id_key = "Part Number", name_key = "Part Name" } }
+
+            [type_mapping]
+            Quantity = "Number"
+            "#,
+        );
+        assert_example_rules(rules.unwrap());
+    }
+
+    #[test]
+    fn yaml() {
+        let rules = Rules::from_yaml(
+            r#"
+            type_mapping:
+              Quantity: Number
+            sheet_selection:
+              Name: Indented BOM
+            child_identification_policy:
+              OrderedLevelKey: level
+            level_validation: Strict
+            output_rules:
+              ItemSync:
+                id_key: Part Number
+                name_key: Part Name
+            "#,
+        );
+        assert_example_rules(rules.unwrap());
+    }
+
+    fn invalid_fields(rules: error::Result<Rules>) -> Vec<String> {
+        let payload = match rules {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        payload.field_violations.into_iter().map(|violation| violation.field.unwrap()).collect()
+    }
+
+    #[test]
+    fn reports_path_to_bad_field() {
+        let rules = Rules::from_json(
+            r#"{
+                "child_identification_policy": { "OrderedLevelKey": "level" },
+                "output_rules": { "ItemSync": { "id_key": "Part Number", "quantity": "Qty" } }
+            }"#,
+        );
+        assert_eq!(invalid_fields(rules), vec!["output_rules.ItemSync.quantity"]);
+
+        let rules = Rules::from_json(
+            r#"{
+                "type_mapping": { "Quantity": "Float" },
+                "child_identification_policy": { "OrderedLevelKey": "level" },
+                "output_rules": { "ItemSync": { "id_key": "Part Number" } }
+            }"#,
+        );
+        assert_eq!(invalid_fields(rules), vec!["type_mapping.Quantity"]);
+    }
+
+    #[test]
+    fn reports_invalid_values() {
+        let rules = Rules::from_json(
+            r#"{
+                "child_identification_policy": { "Absolute": { "id_key": "PN", "parent_key": "PN" } },
+                "output_rules": { "ItemSync": { "id_key": " " } }
+            }"#,
+        );
+        assert_eq!(
+            invalid_fields(rules),
+            vec!["child_identification_policy.Absolute.parent_key", "output_rules.ItemSync.id_key"]
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_aliases() {
+        let rules = Rules::from_yaml(
+            r#"
+            header_aliases:
+              Part Number: [PN]
+              Part Name: [p/n]
+            child_identification_policy:
+              OrderedLevelKey: level
+            output_rules:
+              ItemSync:
+                id_key: Part Number
+            "#,
+        );
+        let fields = invalid_fields(rules);
+        assert_eq!(fields.len(), 1);
+        assert!(fields[0].starts_with("header_aliases."));
+    }
+
+    #[test]
+    fn rejects_ambiguous_number_formats() {
+        let rules = Rules::from_yaml(
+            r#"
+            number_formats:
+              Quantity:
+                decimal_separator: ","
+                thousands_separator: ","
+            child_identification_policy:
+              OrderedLevelKey: level
+            output_rules:
+              ItemSync:
+                id_key: Part Number
+            "#,
+        );
+        assert_eq!(invalid_fields(rules), vec!["number_formats.Quantity.thousands_separator"]);
+    }
+}
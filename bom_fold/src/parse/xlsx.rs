@@ -1 +1,498 @@
-//! TODO: Parser for chaperone XLSX
+//! XLSX parsing for the chaperone.
+
+use crate::transform::{
+    FlatData, HeaderRow, NumberFormat, Rules, SheetSelection, Value, ValueType,
+};
+use calamine::{open_workbook_from_rs, Data, Range, Reader, Xlsx};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use std::{borrow::Cow, io::Cursor};
+
+impl FlatData<'_> {
+    /// Creates `FlatData` from the worksheet(s) of an XLSX workbook buffer selected by the
+    /// `SheetSelection` in the `Rules`.
+    ///
+    /// The headers and footer rows of each worksheet are found like for CSV, and the headers are
+    /// matched against the keys in the `Rules` the same way. Native numeric cells become
+    /// `Value::Number` and string cells become `Value::Text` unless the type mapping in the `Rules`
+    /// says otherwise.
+    pub fn from_xlsx<'a>(data: &'a [u8], rules: &Rules) -> Result<FlatData<'a>> {
+        let mut workbook: Xlsx<_> = open_workbook_from_rs(Cursor::new(data))?;
+        let sheet_names = workbook.sheet_names();
+        let selected_sheet_names = match rules.sheet_selection {
+            SheetSelection::First => sheet_names.into_iter().take(1).collect::<Vec<_>>(),
+            SheetSelection::Name(ref name) => {
+                if !sheet_names.contains(name) {
+                    return Err(Error::not_found(format!(
+                        "Worksheet {name:?} not found, available worksheets are {sheet_names:?}"
+                    )));
+                }
+                vec![name.clone()]
+            }
+            SheetSelection::Index(index) => match sheet_names.get(index) {
+                Some(name) => vec![name.clone()],
+                None => {
+                    return Err(error::out_of_range!(
+                        format!("Workbook only has {} worksheet(s)", sheet_names.len()),
+                        index
+                    ))
+                }
+            },
+            SheetSelection::All => sheet_names,
+        };
+        if selected_sheet_names.is_empty() {
+            return Err(Error::invalid_argument("Workbook does not contain any worksheets"));
+        }
+
+        let mut worksheets = selected_sheet_names
+            .iter()
+            .map(|sheet_name| {
+                let range = workbook.worksheet_range(sheet_name)?;
+                Self::from_worksheet(sheet_name, &range, rules)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let flat_data = match worksheets.len() {
+            1 => worksheets.remove(0),
+            _ => Self::concat_worksheets(worksheets, rules),
+        };
+        super::finalize(flat_data, rules)
+    }
+
+    /// Creates `FlatData` from the named worksheet with the headers in its first row, keeping the
+    /// headers as is and converting the cells without a type mapping. Used for tables written by
+    /// this crate rather than arbitrary input, so no `Rules` are needed.
+    pub(crate) fn from_xlsx_table(data: &[u8], sheet_name: &str) -> Result<FlatData<'static>> {
+        let mut workbook: Xlsx<_> = open_workbook_from_rs(Cursor::new(data))?;
+        let range = workbook.worksheet_range(sheet_name)?;
+        let origin = range.start().unwrap_or((0, 0));
+        let mut rows = range.rows().enumerate();
+        let keys = match rows.next() {
+            Some((_, row)) => row.iter().map(|cell| Cow::Owned(cell.to_string())).collect(),
+            None => return Ok(FlatData::default()),
+        };
+        let mut records = Vec::new();
+        let mut sources = Vec::new();
+        for (row_offset, row) in rows {
+            let row_index = origin.0 as usize + row_offset;
+            let record = row
+                .iter()
+                .enumerate()
+                .map(|(column_offset, cell)| {
+                    Self::make_flat_data_value(cell, None, None).map_err(|description| {
+                        let column = origin.1 as usize + column_offset;
+                        Error::invalid_argument(format!(
+                            "{}: {description}",
+                            cell_reference(sheet_name, row_index, column)
+                        ))
+                    })
+                })
+                .collect::<Result<_>>()?;
+            records.push(record);
+            sources.push(format!("'{sheet_name}' row {}", row_index + 1));
+        }
+        Ok(FlatData { keys, records, sources, ..Default::default() })
+    }
+
+    /// Concatenates the records of several worksheets. Headers are unioned by name in order of
+    /// first appearance, and cells for headers missing from a worksheet are filled as if they were
+    /// empty. Each worksheet starts a new part, so that its items are folded on their own.
+    fn concat_worksheets(worksheets: Vec<FlatData<'static>>, rules: &Rules) -> FlatData<'static> {
+        let mut keys: Vec<Cow<'static, str>> = Vec::new();
+        for worksheet in worksheets.iter() {
+            for key in worksheet.keys.iter() {
+                if !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+        let empty_values = keys
+            .iter()
+            .map(|key| {
+                let value_type = rules.value_type(key);
+                Self::make_flat_data_value(&Data::Empty, value_type, None)
+                    .unwrap_or_else(|_| Value::text(""))
+            })
+            .collect::<Vec<_>>();
+
+        let mut records = Vec::with_capacity(worksheets.iter().map(|w| w.records.len()).sum());
+        let mut sources = Vec::with_capacity(records.capacity());
+        let mut part_starts = Vec::with_capacity(worksheets.len());
+        for worksheet in worksheets {
+            part_starts.push(records.len());
+            sources.extend(worksheet.sources);
+            let positions = keys
+                .iter()
+                .map(|key| worksheet.keys.iter().position(|k| k == key))
+                .collect::<Vec<_>>();
+            for mut record in worksheet.records {
+                records.push(
+                    positions
+                        .iter()
+                        .zip(empty_values.iter())
+                        .map(|(position, empty)| match position {
+                            Some(index) => std::mem::replace(&mut record[*index], Value::text("")),
+                            None => empty.clone(),
+                        })
+                        .collect(),
+                );
+            }
+        }
+        FlatData { keys, records, sources, part_starts }
+    }
+
+    /// Converts a single worksheet into `FlatData`, collecting every cell that fails to convert
+    /// into a single error.
+    fn from_worksheet(
+        sheet_name: &str,
+        range: &Range<Data>,
+        rules: &Rules,
+    ) -> Result<FlatData<'static>> {
+        let origin = range.start().unwrap_or((0, 0));
+        let rows = range.rows().collect::<Vec<_>>();
+        let header_row = match rules.header_row {
+            HeaderRow::Detect => super::detect_header_row(
+                rows.iter().map(|row| row.iter().map(ToString::to_string)),
+                rules,
+            ),
+            // The range starts at the first non-empty row of the worksheet.
+            HeaderRow::Index(index) => match index.checked_sub(origin.0 as usize) {
+                Some(row) if row < rows.len() => row,
+                Some(_) => {
+                    return Err(error::out_of_range!(
+                        format!(
+                            "Worksheet {sheet_name:?} only has {} row(s)",
+                            origin.0 as usize + rows.len()
+                        ),
+                        index
+                    ))
+                }
+                None => {
+                    return Err(Error::invalid_argument(format!(
+                    "Row {} of worksheet {sheet_name:?} is blank, so it can't be the header row",
+                    index + 1
+                )))
+                }
+            },
+        };
+        let headers = match rows.get(header_row) {
+            Some(header_row) => {
+                super::canonicalize_headers(header_row.iter().map(ToString::to_string), rules)?
+            }
+            None => return Ok(FlatData::default()),
+        };
+        let rows = &rows[header_row + 1..];
+        let footer_rows = super::count_footer_rows(rows, &headers, rules, |row, column| {
+            row.get(column).map(ToString::to_string).unwrap_or_default().trim().is_empty()
+        });
+        let rows = &rows[..rows.len() - footer_rows];
+
+        let mut field_violations = Vec::new();
+        let mut records = Vec::with_capacity(rows.len());
+        let mut sources = Vec::with_capacity(rows.len());
+        for (row_offset, row) in rows.iter().enumerate() {
+            let row_index = origin.0 as usize + header_row + row_offset + 1;
+            let mut record = Vec::with_capacity(row.len());
+            for (column_offset, cell) in row.iter().enumerate() {
+                let key = headers.get(column_offset);
+                let value_type = key.and_then(|key| rules.value_type(key));
+                let number_format = key.and_then(|key| rules.number_format(key));
+                match Self::make_flat_data_value(cell, value_type, number_format) {
+                    Ok(value) => record.push(value),
+                    Err(description) => {
+                        let column = origin.1 as usize + column_offset;
+                        field_violations.push(FieldViolation {
+                            field: Some(cell_reference(sheet_name, row_index, column)),
+                            description: Some(match key {
+                                Some(key) => format!("{key:?}: {description}"),
+                                None => description,
+                            }),
+                        })
+                    }
+                }
+            }
+            records.push(record);
+            sources.push(format!("'{sheet_name}' row {}", row_index + 1));
+        }
+
+        if !field_violations.is_empty() {
+            return Err(Error::invalid_argument_with(
+                format!(
+                    "Failed to parse {} cell(s) in sheet {sheet_name:?}",
+                    field_violations.len()
+                ),
+                Some(BadRequest { field_violations }),
+            ));
+        }
+        Ok(FlatData { keys: headers, records, sources, ..Default::default() })
+    }
+
+    /// Converts a typed XLSX cell into a `Value` according to the type mapping for its column.
+    /// Without one, only numeric cells become numbers and every other cell is kept as text, with
+    /// dates written as ISO 8601 instead of their Excel serial.
+    /// The number format only applies to numbers stored as text, since numeric cells don't depend
+    /// on the locale. Returns a description of the problem if the cell can't be converted.
+    fn make_flat_data_value(
+        cell: &Data,
+        value_type: Option<&ValueType>,
+        number_format: Option<&NumberFormat>,
+    ) -> std::result::Result<Value<'static>, String> {
+        Ok(match (value_type, cell) {
+            (_, Data::Error(e)) => return Err(format!("Cell contains an error value: {e}")),
+            (Some(ValueType::Number) | None, Data::Int(n)) => Value::Number(*n as f64),
+            (Some(ValueType::Number) | None, Data::Float(n)) => Value::Number(*n),
+            (Some(ValueType::Integer), Data::Int(n)) => Value::Integer(*n),
+            // Excel stores all numbers as floats.
+            (Some(ValueType::Integer), Data::Float(n)) if n.fract() == 0.0 => {
+                Value::Integer(*n as i64)
+            }
+            (Some(ValueType::Boolean), Data::Bool(b)) => Value::Boolean(*b),
+            (Some(ValueType::Date), Data::DateTime(datetime)) if datetime.is_datetime() => {
+                match datetime.as_datetime() {
+                    Some(datetime) => Value::Date(datetime.date()),
+                    None => return Err(format!("Cell contains an invalid date: {datetime}")),
+                }
+            }
+            (None | Some(ValueType::Text), Data::DateTime(datetime)) => {
+                match datetime.as_datetime() {
+                    Some(datetime) if datetime.time() == chrono::NaiveTime::MIN => {
+                        Value::text_owned(datetime.format("%Y-%m-%d"))
+                    }
+                    Some(datetime) => Value::text_owned(datetime.format("%Y-%m-%dT%H:%M:%S")),
+                    None => Value::text_owned(datetime),
+                }
+            }
+            (None | Some(ValueType::Text), other) => Value::text_owned(other),
+            (Some(_), Data::Empty) => Value::Empty,
+            (Some(value_type), Data::String(text) | Data::DateTimeIso(text)) => {
+                value_type.parse(text, number_format)?
+            }
+            (Some(value_type), other) => {
+                return Err(format!("Expected a value of type {value_type:?} but found {other:?}"))
+            }
+        })
+    }
+}
+
+/// Formats the zero-based cell position as an A1-style reference, e.g. `'BOM'!C5`.
+fn cell_reference(sheet_name: &str, row: usize, column: usize) -> String {
+    let mut column_name = Vec::new();
+    let mut remaining = column + 1;
+    while remaining > 0 {
+        remaining -= 1;
+        column_name.push(b'A' + (remaining % 26) as u8);
+        remaining /= 26;
+    }
+    column_name.reverse();
+    let column_name = String::from_utf8_lossy(&column_name);
+    format!("'{sheet_name}'!{column_name}{}", row + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::{
+        ChildIdentificationPolicy, FlatData, HeaderRow, LevelValidation, OutputRules, Rules,
+        SheetSelection, Value, ValueType,
+    };
+    use crate::ItemSyncFormatRules;
+    use calamine::{Data, ExcelDateTime, ExcelDateTimeType};
+    use error::Error;
+    use pretty_assertions::assert_eq;
+
+    fn rules() -> Rules {
+        Rules {
+            type_mapping: Some([("Quantity".to_string(), ValueType::Number)].into_iter().collect()),
+            number_formats: None,
+            header_aliases: None,
+            header_row: HeaderRow::Detect,
+            drop_footer_rows: true,
+            sheet_selection: SheetSelection::First,
+            child_identification_policy: ChildIdentificationPolicy::OrderedLevelKey(
+                "level".to_string(),
+            ),
+            level_validation: LevelValidation::Lenient,
+            consolidation: None,
+            rollups: Vec::new(),
+            output_rules: OutputRules::ItemSync(ItemSyncFormatRules {
+                id_key: "Part Number".to_string(),
+                name_key: Some("Part Name".to_string()),
+                quantity_key: Some("Quantity".to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn reads_first_worksheet() {
+        let data = include_bytes!("../../test_data/simple.xlsx");
+        let flat_data = FlatData::from_xlsx(data, &rules()).unwrap();
+        assert_eq!(flat_data.keys, vec!["Part Number", "Part Name", "Quantity", "level"]);
+        assert_eq!(
+            flat_data.records,
+            vec![
+                vec![
+                    Value::text("A-100"),
+                    Value::text("Assembly"),
+                    Value::Number(1.0),
+                    Value::Number(1.0)
+                ],
+                vec![
+                    Value::Number(2001.0),
+                    Value::text("Bracket"),
+                    Value::Number(4.0),
+                    Value::Number(2.0)
+                ],
+                vec![
+                    Value::text("B-300"),
+                    Value::text("Bolt"),
+                    Value::Number(2.0),
+                    Value::Number(2.0)
+                ],
+            ]
+        );
+        assert_eq!(flat_data.sources, ["'BOM' row 2", "'BOM' row 3", "'BOM' row 4"]);
+    }
+
+    #[test]
+    fn text_mapping_stringifies_numeric_cells() {
+        let mut rules = rules();
+        rules.type_mapping.as_mut().unwrap().insert("Part Number".to_string(), ValueType::Text);
+        let data = include_bytes!("../../test_data/simple.xlsx");
+        let flat_data = FlatData::from_xlsx(data, &rules).unwrap();
+        assert_eq!(flat_data.records[1][0], Value::text("2001"));
+    }
+
+    #[test]
+    fn reports_bad_cell_coordinates() {
+        let data = include_bytes!("../../test_data/bad_cells.xlsx");
+        let payload = match FlatData::from_xlsx(data, &rules()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        let fields = payload
+            .field_violations
+            .into_iter()
+            .map(|violation| violation.field.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["'BOM'!C3", "'BOM'!C4"]);
+    }
+
+    #[test]
+    fn selects_sheet_by_name_or_index() {
+        let data = include_bytes!("../../test_data/cover_sheet.xlsx");
+        let mut rules = rules();
+        rules.sheet_selection = SheetSelection::Name("Indented BOM".to_string());
+        let by_name = FlatData::from_xlsx(data, &rules).unwrap();
+        assert_eq!(by_name.keys, vec!["Part Number", "Part Name", "Quantity", "level"]);
+        assert_eq!(by_name.records.len(), 2);
+
+        rules.sheet_selection = SheetSelection::Index(1);
+        assert_eq!(FlatData::from_xlsx(data, &rules).unwrap(), by_name);
+
+        rules.sheet_selection = SheetSelection::Index(2);
+        assert!(matches!(FlatData::from_xlsx(data, &rules), Err(Error::OutOfRange(_))));
+        rules.sheet_selection = SheetSelection::Name("BOM".to_string());
+        assert!(matches!(FlatData::from_xlsx(data, &rules), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn concatenates_all_sheets() {
+        let data = include_bytes!("../../test_data/assemblies.xlsx");
+        let mut rules = rules();
+        rules.sheet_selection = SheetSelection::All;
+        let flat_data = FlatData::from_xlsx(data, &rules).unwrap();
+        assert_eq!(flat_data.keys, vec!["Part Number", "Part Name", "Quantity", "level", "Notes"]);
+        assert_eq!(
+            flat_data.records,
+            vec![
+                vec![
+                    Value::text("A-100"),
+                    Value::text("Assembly A"),
+                    Value::Number(1.0),
+                    Value::Number(1.0),
+                    Value::text("")
+                ],
+                vec![
+                    Value::text("A-200"),
+                    Value::text("Bracket"),
+                    Value::Number(4.0),
+                    Value::Number(2.0),
+                    Value::text("")
+                ],
+                vec![
+                    Value::text("B-100"),
+                    Value::text("Assembly B"),
+                    Value::Number(1.0),
+                    Value::Number(1.0),
+                    Value::text("Rev C")
+                ],
+                vec![
+                    Value::text("B-200"),
+                    Value::text("Bolt"),
+                    Value::Number(8.0),
+                    Value::Number(2.0),
+                    Value::text("")
+                ],
+            ]
+        );
+        assert_eq!(flat_data.part_starts, vec![0, 2]);
+        let folded_data = crate::transform(&flat_data, &rules).unwrap();
+        assert_eq!(folded_data.top_level_nodes.len(), 2);
+    }
+
+    #[test]
+    fn skips_preamble_and_footer_rows() {
+        let data = include_bytes!("../../test_data/preamble.xlsx");
+        let flat_data = FlatData::from_xlsx(data, &rules()).unwrap();
+        assert_eq!(flat_data.keys, vec!["Part Number", "Part Name", "Quantity", "level"]);
+        assert_eq!(
+            flat_data.records,
+            vec![
+                vec![
+                    Value::text("A-100"),
+                    Value::text("Assembly"),
+                    Value::Number(1.0),
+                    Value::Number(1.0)
+                ],
+                vec![
+                    Value::text("B-200"),
+                    Value::text("Bolt"),
+                    Value::Number(4.0),
+                    Value::Number(2.0)
+                ],
+            ]
+        );
+
+        assert_eq!(flat_data.sources, ["'BOM' row 5", "'BOM' row 6"]);
+
+        let fixed_row = Rules { header_row: HeaderRow::Index(3), ..rules() };
+        assert_eq!(FlatData::from_xlsx(data, &fixed_row).unwrap(), flat_data);
+        let past_end = Rules { header_row: HeaderRow::Index(99), ..rules() };
+        assert!(matches!(FlatData::from_xlsx(data, &past_end), Err(Error::OutOfRange(_))));
+    }
+
+    #[test]
+    fn cell_reference() {
+        assert_eq!(super::cell_reference("Sheet1", 0, 0), "'Sheet1'!A1");
+        assert_eq!(super::cell_reference("Sheet1", 9, 25), "'Sheet1'!Z10");
+        assert_eq!(super::cell_reference("Sheet1", 1, 26), "'Sheet1'!AA2");
+    }
+
+    #[test]
+    fn keeps_untyped_cells_as_text() {
+        let value =
+            |cell: &Data, value_type| FlatData::make_flat_data_value(cell, value_type, None);
+        let boolean = Data::Bool(true);
+        let datetime =
+            Data::DateTime(ExcelDateTime::new(44835.5, ExcelDateTimeType::DateTime, false));
+        assert_eq!(value(&boolean, None), Ok(Value::text("true")));
+        let date = Data::DateTime(ExcelDateTime::new(44835.0, ExcelDateTimeType::DateTime, false));
+        assert_eq!(value(&datetime, None), Ok(Value::text("2022-10-01T12:00:00")));
+        assert_eq!(value(&date, None), Ok(Value::text("2022-10-01")));
+        assert_eq!(value(&boolean, Some(&ValueType::Boolean)), Ok(Value::Boolean(true)));
+        assert_eq!(
+            value(&datetime, Some(&ValueType::Date)),
+            Ok(Value::Date(chrono::NaiveDate::from_ymd_opt(2022, 10, 1).unwrap()))
+        );
+    }
+}
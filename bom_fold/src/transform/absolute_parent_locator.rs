@@ -1,32 +1,379 @@
-///! This modules is still under construction.
+use crate::transform::{
+    data::{FlatData, FoldedData, Node, Value},
+    keys::find_key,
+};
+use error::{
+    error_details::{
+        bad_request::FieldViolation, precondition_failure, BadRequest, PreconditionFailure,
+    },
+    Error, Result,
+};
 use serde::Deserialize;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ops::Range,
+};
 
 /// Locates the parent item in a flat item hierarchy by indexing based on some value.
 /// An example could be, each row in the flat item hierarchy has an attribute called "Parent Part
 /// Number" which references the "Part Number" attribute of a different item in the flat file.
-#[derive(Deserialize)]
-pub struct AbsoluteParentLocator {}
-
-// pub struct Computation<'a> {
-//     pub inputs: Vec<Expression<'a>>,
-//     pub operation: OpName,
-// }
-//
-// pub enum Expression<'a> {
-//     Literal(Value<'a>),
-//     /// Reference to value in the same row.
-//     ReferenceKey(Cow<'a, str>),
-//     /// Some computation
-//     Computation(Computation<'a>),
-// }
-//
-// pub enum OpName {
-//     Equals,
-//     Compare,
-//     Strip,
-//     StripStart,
-//     StripEnd,
-//     Trim,
-//     Concat,
-//     ToString,
-// }
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AbsoluteParentLocator {
+    /// Key of the attribute that identifies an item, e.g. "Part Number".
+    pub id_key: String,
+
+    /// Key of the attribute that references the `id_key` value of the parent item, e.g. "Parent
+    /// Part Number". Items where this is empty are top level items.
+    pub parent_key: String,
+}
+
+/// Folds the flat data by looking up each record's parent by id. Record order is irrelevant.
+///
+/// If several records share an id, the children referencing that id are attached to each of them.
+/// Parent references that form a cycle (including records that are their own parent) are rejected
+/// with a `FailedPrecondition` error listing the path of ids around each cycle.
+///
+/// Each of the `FlatData::parts` is folded on its own, so parent references never cross from one
+/// part into another.
+pub fn fold<'data>(
+    flat_data: &'data FlatData,
+    locator: &AbsoluteParentLocator,
+) -> Result<FoldedData<'data>> {
+    if flat_data.records.is_empty() || flat_data.keys.is_empty() {
+        return Ok(FoldedData::default());
+    }
+
+    let id_index = find_key(&flat_data.keys, &locator.id_key, "id key")?;
+    let parent_index = find_key(&flat_data.keys, &locator.parent_key, "parent key")?;
+
+    let mut top_level_nodes = Vec::new();
+    for records in flat_data.parts() {
+        top_level_nodes.extend(fold_part(flat_data, records, locator, id_index, parent_index)?);
+    }
+    Ok(FoldedData { top_level_nodes, attribute_keys: &flat_data.keys })
+}
+
+/// Folds the `records` of one part of the flat data into its top level nodes.
+fn fold_part<'data>(
+    flat_data: &'data FlatData,
+    records: Range<usize>,
+    locator: &AbsoluteParentLocator,
+    id_index: usize,
+    parent_index: usize,
+) -> Result<Vec<Node<'data>>> {
+    let mut record_indices_by_id: HashMap<String, Vec<usize>> = HashMap::new();
+    for index in records.clone() {
+        let id = reference(&flat_data.records[index][id_index]);
+        record_indices_by_id.entry(id).or_default().push(index);
+    }
+
+    let mut top_level_indices = Vec::new();
+    let mut children_by_id: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut field_violations = Vec::new();
+    for index in records.clone() {
+        let parent_id = reference(&flat_data.records[index][parent_index]);
+        if parent_id.is_empty() {
+            top_level_indices.push(index);
+        } else if record_indices_by_id.contains_key(&parent_id) {
+            children_by_id.entry(parent_id).or_default().push(index);
+        } else {
+            field_violations.push(FieldViolation {
+                field: Some(flat_data.source(index)),
+                description: Some(format!(
+                    "{:?} = {parent_id:?} does not match the {:?} of any row",
+                    locator.parent_key, locator.id_key
+                )),
+            });
+        }
+    }
+    if !field_violations.is_empty() {
+        return Err(Error::invalid_argument_with(
+            format!("Found {} row(s) with dangling parent references", field_violations.len()),
+            Some(BadRequest { field_violations }),
+        ));
+    }
+
+    let cycles = find_cycles(flat_data, records, id_index, &record_indices_by_id, &children_by_id);
+    if !cycles.is_empty() {
+        let violations = cycles
+            .into_iter()
+            .map(|cycle| precondition_failure::Violation {
+                r#type: Some(if cycle.len() == 2 { "SELF_REFERENCE" } else { "CYCLE" }.to_string()),
+                subject: Some(cycle.join(" -> ")),
+                description: Some(format!(
+                    "{:?} references form a cycle, so these items can never be placed under a top \
+                     level item",
+                    locator.parent_key
+                )),
+            })
+            .collect::<Vec<_>>();
+        return Err(Error::failed_precondition_with(
+            format!("Found {} cycle(s) in the parent references", violations.len()),
+            Some(PreconditionFailure { violations }),
+        ));
+    }
+
+    Ok(top_level_indices
+        .into_iter()
+        .map(|index| make_node(flat_data, id_index, &children_by_id, index))
+        .collect())
+}
+
+/// Visitation state of an id during cycle detection.
+enum VisitState {
+    /// The id is on the current DFS path.
+    InProgress,
+    /// The id and all of its descendents have been visited.
+    Done,
+}
+
+/// Finds cycles in the id graph of the `records` where each id points to the ids of the records
+/// that reference it as a parent. Each cycle is returned as the path of ids starting and ending at
+/// the same id.
+///
+/// The DFS is iterative so that very deep (or broken) hierarchies can't overflow the stack.
+fn find_cycles(
+    flat_data: &FlatData,
+    records: Range<usize>,
+    id_index: usize,
+    record_indices_by_id: &HashMap<String, Vec<usize>>,
+    children_by_id: &HashMap<String, Vec<usize>>,
+) -> Vec<Vec<String>> {
+    let child_ids = |id: &str| -> Vec<String> {
+        children_by_id
+            .get(id)
+            .map(|indices| {
+                indices
+                    .iter()
+                    .map(|index| reference(&flat_data.records[*index][id_index]))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let mut cycles = Vec::new();
+    let mut states: HashMap<String, VisitState> =
+        HashMap::with_capacity(record_indices_by_id.len());
+    for record in flat_data.records[records].iter() {
+        let start = reference(&record[id_index]);
+        if states.contains_key(&start) {
+            continue;
+        }
+        states.insert(start.clone(), VisitState::InProgress);
+        // Path of ids from `start` along with the children of each that are still to be visited.
+        let mut path = vec![(child_ids(&start), start)];
+        while let Some((remaining_children, id)) = path.last_mut() {
+            let child = match remaining_children.pop() {
+                Some(child) => child,
+                None => {
+                    states.insert(id.clone(), VisitState::Done);
+                    path.pop();
+                    continue;
+                }
+            };
+            match states.entry(child.clone()) {
+                Entry::Occupied(entry) => {
+                    if let VisitState::InProgress = entry.get() {
+                        let cycle_start = path.iter().position(|(_, id)| id == &child).unwrap_or(0);
+                        let mut cycle = path[cycle_start..]
+                            .iter()
+                            .map(|(_, id)| id.clone())
+                            .collect::<Vec<_>>();
+                        cycle.push(child);
+                        cycles.push(cycle);
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(VisitState::InProgress);
+                    let mut grandchildren = child_ids(&child);
+                    // Children are popped from the back, so reverse to visit them in record order.
+                    grandchildren.reverse();
+                    path.push((grandchildren, child));
+                }
+            }
+        }
+    }
+    cycles
+}
+
+/// Recursively builds the node for the record at `index` along with all of its descendents.
+fn make_node<'data>(
+    flat_data: &'data FlatData,
+    id_index: usize,
+    children_by_id: &HashMap<String, Vec<usize>>,
+    index: usize,
+) -> Node<'data> {
+    let attributes = &flat_data.records[index];
+    let children = children_by_id
+        .get(&reference(&attributes[id_index]))
+        .map(|child_indices| {
+            child_indices
+                .iter()
+                .map(|child_index| make_node(flat_data, id_index, children_by_id, *child_index))
+                .collect()
+        })
+        .unwrap_or_default();
+    Node { attributes, children }
+}
+
+/// Normalizes an id or parent reference so that e.g. `Text("1001 ")` matches `Number(1001.0)`.
+fn reference(value: &Value) -> String {
+    value.to_string().trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AbsoluteParentLocator;
+    use crate::transform::{FlatData, FoldedData, Node, Value};
+    use error::Error;
+    use pretty_assertions::assert_eq;
+    use std::borrow::Cow;
+
+    fn locator() -> AbsoluteParentLocator {
+        AbsoluteParentLocator { id_key: "id".to_string(), parent_key: "parent".to_string() }
+    }
+
+    fn keys() -> Vec<Cow<'static, str>> {
+        ["id", "parent"].into_iter().map(Cow::from).collect()
+    }
+
+    #[test]
+    fn unordered_records() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("C"), Value::text("B")],
+                vec![Value::text("B"), Value::text("A")],
+                vec![Value::text("D"), Value::text("A")],
+                vec![Value::text("A"), Value::text("")],
+                vec![Value::Number(2.0), Value::text("")],
+                vec![Value::text("E"), Value::text("2")],
+            ],
+            ..Default::default()
+        };
+        let output = FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![
+                Node {
+                    attributes: &input.records[3],
+                    children: vec![
+                        Node {
+                            attributes: &input.records[1],
+                            children: vec![Node {
+                                attributes: &input.records[0],
+                                children: Vec::new(),
+                            }],
+                        },
+                        Node { attributes: &input.records[2], children: Vec::new() },
+                    ],
+                },
+                Node {
+                    attributes: &input.records[4],
+                    children: vec![Node { attributes: &input.records[5], children: Vec::new() }],
+                },
+            ],
+        };
+        assert_eq!(super::fold(&input, &locator()).unwrap(), output);
+    }
+
+    #[test]
+    fn dangling_parent_reference() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("A"), Value::text("")],
+                vec![Value::text("B"), Value::text("A")],
+                vec![Value::text("C"), Value::text("Z")],
+            ],
+            ..Default::default()
+        };
+        let payload = match super::fold(&input, &locator()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(payload.field_violations.len(), 1);
+        assert_eq!(payload.field_violations[0].field.as_deref(), Some("row 3"));
+    }
+
+    #[test]
+    fn parent_reference_into_another_part() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("A"), Value::text("")],
+                vec![Value::text("B"), Value::text("")],
+                vec![Value::text("C"), Value::text("A")],
+            ],
+            part_starts: vec![0, 1],
+            ..Default::default()
+        };
+        let payload = match super::fold(&input, &locator()) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(payload.field_violations.len(), 1);
+        assert_eq!(payload.field_violations[0].field.as_deref(), Some("row 3"));
+    }
+
+    fn cycle_subjects(input: &FlatData) -> Vec<(String, String)> {
+        let payload = match super::fold(input, &locator()) {
+            Err(Error::FailedPrecondition(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected failed precondition, got {other:?}"),
+        };
+        payload
+            .violations
+            .into_iter()
+            .map(|violation| (violation.r#type.unwrap(), violation.subject.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn unreachable_cycle() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("R"), Value::text("")],
+                vec![Value::text("A"), Value::text("C")],
+                vec![Value::text("B"), Value::text("A")],
+                vec![Value::text("C"), Value::text("B")],
+                vec![Value::text("D"), Value::text("B")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            cycle_subjects(&input),
+            vec![("CYCLE".to_string(), "A -> B -> C -> A".to_string())]
+        );
+    }
+
+    #[test]
+    fn self_reference() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("A"), Value::text("")],
+                vec![Value::text("B"), Value::text("B")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            cycle_subjects(&input),
+            vec![("SELF_REFERENCE".to_string(), "B -> B".to_string())]
+        );
+    }
+
+    #[test]
+    fn cycle_reachable_through_repeated_id() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("A"), Value::text("")],
+                vec![Value::text("B"), Value::text("A")],
+                vec![Value::text("A"), Value::text("B")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(cycle_subjects(&input), vec![("CYCLE".to_string(), "A -> B -> A".to_string())]);
+    }
+}
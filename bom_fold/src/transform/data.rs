@@ -1,18 +1,65 @@
-use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
+use crate::transform::NumberFormat;
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{borrow::Cow, fmt, ops::Range};
 
 /// Abstract input data, extracted from some flat format like excel or CSV.
-#[derive(Debug, PartialEq)]
+///
+/// Construct it with `FlatData::new` or with `..Default::default()` for the fields that aren't
+/// known, so that callers keep compiling when fields are added.
+#[derive(Debug, Default, PartialEq)]
 pub struct FlatData<'a> {
     pub keys: Vec<Cow<'a, str>>,
     pub records: Vec<Vec<Value<'a>>>,
+    /// Where each record was read from, e.g. "line 9" of a CSV file or "'BOM' row 9" of a
+    /// worksheet, so that problems with a record can be traced back to the input. Empty for data
+    /// that wasn't read from a file.
+    pub sources: Vec<String>,
+    /// Indices of the records that start a part of the input that is folded on its own, e.g. the
+    /// first record of each worksheet when several are read. Empty if all records are folded
+    /// together.
+    pub part_starts: Vec<usize>,
+}
+
+impl<'a> FlatData<'a> {
+    /// Constructs `FlatData` that wasn't read from a file, so has no sources.
+    pub fn new(keys: Vec<Cow<'a, str>>, records: Vec<Vec<Value<'a>>>) -> Self {
+        FlatData { keys, records, ..Default::default() }
+    }
+
+    /// Describes where the record at the index was read from, or its position among the records,
+    /// e.g. "row 3", if that isn't known.
+    pub fn source(&self, index: usize) -> String {
+        match self.sources.get(index) {
+            Some(source) => source.clone(),
+            None => format!("row {}", index + 1),
+        }
+    }
+
+    /// Index ranges of the records of each part that is folded on its own, see `part_starts`.
+    /// Parts are never empty, so there are none without records.
+    pub fn parts(&self) -> Vec<Range<usize>> {
+        let mut bounds = vec![0];
+        bounds.extend(self.part_starts.iter().copied().filter(|start| *start < self.records.len()));
+        bounds.push(self.records.len());
+        bounds.sort_unstable();
+        bounds.dedup();
+        bounds.windows(2).map(|bounds| bounds[0]..bounds[1]).collect()
+    }
 }
 
 /// Individual parsed from the flat file.
-#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize)]
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub enum Value<'a> {
     Text(Cow<'a, str>),
     Number(f64),
+    /// Whole number, which unlike `Number` is exact beyond 2^53, e.g. for long numeric part
+    /// numbers.
+    Integer(i64),
+    Boolean(bool),
+    Date(NaiveDate),
+    /// Missing value of a typed column, e.g. a quantity left blank, as opposed to `Number(0.0)`.
+    Empty,
 }
 
 impl Value<'_> {
@@ -25,13 +72,129 @@ impl Value<'_> {
     pub fn text_owned<S: ToString>(text: S) -> Value<'static> {
         Value::Text(Cow::from(text.to_string()))
     }
+
+    /// Clones the value into one that doesn't borrow from the input.
+    pub fn to_owned_value(&self) -> Value<'static> {
+        match self {
+            Value::Text(text) => Value::text_owned(text),
+            Value::Number(number) => Value::Number(*number),
+            Value::Integer(integer) => Value::Integer(*integer),
+            Value::Boolean(boolean) => Value::Boolean(*boolean),
+            Value::Date(date) => Value::Date(*date),
+            Value::Empty => Value::Empty,
+        }
+    }
+
+    /// The numeric value of a `Number` or `Integer`.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            Value::Integer(integer) => Some(*integer as f64),
+            _ => None,
+        }
+    }
+
+    /// Whether the value is `Empty` or blank text.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::Empty => true,
+            Value::Text(text) => text.trim().is_empty(),
+            _ => false,
+        }
+    }
 }
 
-/// Possible types that a value can take.
+impl fmt::Display for Value<'_> {
+    /// Formats the bare value, e.g. `Text("foo")` as `foo`, `Number(2.0)` as `2`, dates as
+    /// "2024-01-31" and `Empty` as an empty string.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Text(text) => f.write_str(text),
+            Value::Number(number) => write!(f, "{number}"),
+            Value::Integer(integer) => write!(f, "{integer}"),
+            Value::Boolean(boolean) => write!(f, "{boolean}"),
+            Value::Date(date) => write!(f, "{}", date.format("%Y-%m-%d")),
+            Value::Empty => Ok(()),
+        }
+    }
+}
+
+impl Serialize for Value<'_> {
+    /// Serializes the bare value rather than tagged with its type, with dates as "2024-01-31"
+    /// strings and `Empty` as none, e.g. `null` in JSON or an empty CSV field.
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Value::Text(text) => serializer.serialize_str(text),
+            Value::Number(number) => serializer.serialize_f64(*number),
+            Value::Integer(integer) => serializer.serialize_i64(*integer),
+            Value::Boolean(boolean) => serializer.serialize_bool(*boolean),
+            Value::Date(date) => serializer.collect_str(&date.format("%Y-%m-%d")),
+            Value::Empty => serializer.serialize_none(),
+        }
+    }
+}
+
+/// Possible types that a value can take. Blank values of any type other than `Text` are read as
+/// `Value::Empty`.
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize)]
 pub enum ValueType {
     Text,
     Number,
+    Integer,
+    /// "true"/"false", "yes"/"no", "y"/"n" or "1"/"0", in any case.
+    Boolean,
+    /// ISO 8601 dates like "2024-01-31", optionally followed by a time, which is dropped.
+    Date,
+}
+
+impl ValueType {
+    /// Parses the text as this type, reading numbers in the number format if there is one.
+    /// Returns a description of the problem if it can't be parsed.
+    pub fn parse(
+        self,
+        text: &str,
+        number_format: Option<&NumberFormat>,
+    ) -> std::result::Result<Value<'static>, String> {
+        let trimmed = text.trim();
+        if trimmed.is_empty() && self != ValueType::Text {
+            return Ok(Value::Empty);
+        }
+        let number = || match number_format {
+            Some(number_format) => number_format.normalize(trimmed).map(Cow::Owned),
+            None => Ok(Cow::Borrowed(trimmed)),
+        };
+        Ok(match self {
+            ValueType::Text => Value::text_owned(text),
+            ValueType::Number => Value::Number(
+                number()?
+                    .parse()
+                    .map_err(|e| format!("Failed to parse {trimmed:?} as a number: {e}"))?,
+            ),
+            ValueType::Integer => Value::Integer(
+                number()?
+                    .parse()
+                    .map_err(|e| format!("Failed to parse {trimmed:?} as an integer: {e}"))?,
+            ),
+            ValueType::Boolean => match trimmed.to_lowercase().as_str() {
+                "true" | "yes" | "y" | "1" => Value::Boolean(true),
+                "false" | "no" | "n" | "0" => Value::Boolean(false),
+                _ => return Err(format!("Failed to parse {trimmed:?} as a boolean")),
+            },
+            ValueType::Date => Value::Date(parse_date(trimmed).ok_or_else(|| {
+                format!("Failed to parse {trimmed:?} as a date, expected e.g. \"2024-01-31\"")
+            })?),
+        })
+    }
+}
+
+/// Parses an ISO 8601 date, or the date part of an ISO 8601 date and time.
+fn parse_date(text: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().or_else(|| {
+        ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"]
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+            .map(|datetime| datetime.date())
+    })
 }
 
 /// Abstract item hierarchy, the output of folding.
@@ -53,3 +216,38 @@ pub struct Node<'a> {
     /// Descendent nodes.
     pub children: Vec<Node<'a>>,
 }
+
+/// Item hierarchy that owns its keys and values, for hierarchies that aren't folded from a
+/// `FlatData`, e.g. ones read from JSON. Borrow it as a `FoldedData` with [`Self::folded_data`].
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct OwnedFoldedData {
+    pub attribute_keys: Vec<Cow<'static, str>>,
+    pub top_level_nodes: Vec<OwnedNode>,
+}
+
+/// Single element in the owned item hierarchy.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct OwnedNode {
+    pub attributes: Vec<Value<'static>>,
+    pub children: Vec<OwnedNode>,
+}
+
+impl OwnedFoldedData {
+    /// Borrows the hierarchy as a `FoldedData`.
+    pub fn folded_data(&self) -> FoldedData<'_> {
+        FoldedData {
+            attribute_keys: &self.attribute_keys,
+            top_level_nodes: self.top_level_nodes.iter().map(OwnedNode::node).collect(),
+        }
+    }
+}
+
+impl OwnedNode {
+    /// Borrows the node and its descendents as a `Node`.
+    pub fn node(&self) -> Node<'_> {
+        Node {
+            attributes: &self.attributes,
+            children: self.children.iter().map(OwnedNode::node).collect(),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::transform::{
+    data::{FlatData, Value},
+    keys::{find_key, position_of_key},
+};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// Identifies the depth of each item from the indentation of one of its attributes, as exported by
+/// CAD tools like SolidWorks or Inventor, e.g. "Assembly", "..Bracket", "....Bolt".
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndentationRules {
+    /// Key of the indented attribute, e.g. "Part Name".
+    pub key: String,
+
+    /// Characters that count as indentation.
+    #[serde(default = "IndentationRules::default_indent_chars")]
+    pub indent_chars: String,
+
+    /// Number of indentation characters per level.
+    #[serde(default = "IndentationRules::default_indent_width")]
+    pub indent_width: usize,
+
+    /// Key of the attribute that the derived depth is written to, where top level items have
+    /// depth 0.
+    #[serde(default = "IndentationRules::default_level_key")]
+    pub level_key: String,
+}
+
+impl IndentationRules {
+    fn default_indent_chars() -> String {
+        " \t.".to_string()
+    }
+
+    fn default_indent_width() -> usize {
+        1
+    }
+
+    fn default_level_key() -> String {
+        "Indentation Level".to_string()
+    }
+
+    /// Creates rules for the key with the default indentation characters, width and level key.
+    pub fn new<S: ToString>(key: S) -> Self {
+        Self {
+            key: key.to_string(),
+            indent_chars: Self::default_indent_chars(),
+            indent_width: Self::default_indent_width(),
+            level_key: Self::default_level_key(),
+        }
+    }
+}
+
+impl FlatData<'_> {
+    /// Strips the indentation from the indented attribute of every record and records the depth it
+    /// represented as a `Value::Number` under the `level_key`, which is appended to the keys if
+    /// not already present. The result can then be folded by the `level_key`.
+    ///
+    /// The parsers do this automatically when the `Rules` use
+    /// `ChildIdentificationPolicy::IndentedKey`.
+    pub fn extract_indentation(&mut self, rules: &IndentationRules) -> Result<()> {
+        if rules.indent_width == 0 {
+            return Err(error::invalid_argument!(
+                "Indentation width must be at least 1",
+                rules.indent_width
+            ));
+        }
+        let key_index = find_key(&self.keys, &rules.key, "indented key")?;
+        let level_index = match position_of_key(&self.keys, &rules.level_key) {
+            Some(index) => index,
+            None => {
+                self.keys.push(Cow::from(rules.level_key.clone()));
+                for record in self.records.iter_mut() {
+                    record.push(Value::Number(0.0));
+                }
+                self.keys.len() - 1
+            }
+        };
+
+        // Index and indentation of the records that aren't indented by a multiple of the width.
+        let mut uneven = Vec::new();
+        for (index, record) in self.records.iter_mut().enumerate() {
+            let indentation = match record[key_index] {
+                Value::Text(ref mut text) => strip_indentation(text, &rules.indent_chars),
+                _ => 0,
+            };
+            if indentation % rules.indent_width != 0 {
+                uneven.push((index, indentation));
+            }
+            record[level_index] = Value::Number((indentation / rules.indent_width) as f64);
+        }
+        let field_violations = uneven
+            .into_iter()
+            .map(|(index, indentation)| FieldViolation {
+                field: Some(self.source(index)),
+                description: Some(format!(
+                    "{:?} is indented by {indentation} character(s), which is not a multiple of {}",
+                    rules.key, rules.indent_width
+                )),
+            })
+            .collect::<Vec<_>>();
+        if !field_violations.is_empty() {
+            return Err(Error::invalid_argument_with(
+                format!("Found {} row(s) with uneven indentation", field_violations.len()),
+                Some(BadRequest { field_violations }),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Removes the leading indentation characters from the text, returning how many were removed.
+fn strip_indentation(text: &mut Cow<'_, str>, indent_chars: &str) -> usize {
+    let (count, offset) = text
+        .char_indices()
+        .take_while(|(_, c)| indent_chars.contains(*c))
+        .fold((0, 0), |(count, _), (offset, c)| (count + 1, offset + c.len_utf8()));
+    match text {
+        Cow::Borrowed(borrowed) => *borrowed = &borrowed[offset..],
+        Cow::Owned(owned) => {
+            owned.drain(..offset);
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndentationRules;
+    use crate::transform::{ordered_level_key, FlatData, LevelValidation, Node, Value};
+    use error::Error;
+    use pretty_assertions::assert_eq;
+    use std::borrow::Cow;
+
+    fn input(names: &[&'static str]) -> FlatData<'static> {
+        FlatData {
+            keys: ["name", "qty"].into_iter().map(Cow::from).collect(),
+            records: names.iter().map(|name| vec![Value::text(name), Value::Number(1.0)]).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn extracts_depth_and_strips_text() {
+        let mut flat_data = input(&["Assembly", "..Bracket", "....Bolt", "..Nut", "Other"]);
+        flat_data.extract_indentation(&IndentationRules::new("name")).unwrap();
+        assert_eq!(flat_data.keys, vec!["name", "qty", "Indentation Level"]);
+        let names_and_levels = flat_data
+            .records
+            .iter()
+            .map(|record| (record[0].to_string(), record[2].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names_and_levels,
+            vec![
+                ("Assembly".to_string(), Value::Number(0.0)),
+                ("Bracket".to_string(), Value::Number(2.0)),
+                ("Bolt".to_string(), Value::Number(4.0)),
+                ("Nut".to_string(), Value::Number(2.0)),
+                ("Other".to_string(), Value::Number(0.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn folds_by_indentation() {
+        let mut flat_data = input(&["Assembly", "  Bracket", "    Bolt", "  Nut", "Other"]);
+        let rules = IndentationRules { indent_width: 2, ..IndentationRules::new("name") };
+        flat_data.extract_indentation(&rules).unwrap();
+        let folded_data =
+            ordered_level_key::fold(&flat_data, &rules.level_key, LevelValidation::Strict).unwrap();
+        assert_eq!(
+            folded_data.top_level_nodes,
+            vec![
+                Node {
+                    attributes: &flat_data.records[0],
+                    children: vec![
+                        Node {
+                            attributes: &flat_data.records[1],
+                            children: vec![Node {
+                                attributes: &flat_data.records[2],
+                                children: Vec::new(),
+                            }],
+                        },
+                        Node { attributes: &flat_data.records[3], children: Vec::new() },
+                    ],
+                },
+                Node { attributes: &flat_data.records[4], children: Vec::new() },
+            ]
+        );
+    }
+
+    #[test]
+    fn uneven_indentation() {
+        let mut flat_data = input(&["Assembly", "  Bracket", "   Bolt"]);
+        let rules = IndentationRules { indent_width: 2, ..IndentationRules::new("name") };
+        let payload = match flat_data.extract_indentation(&rules) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(payload.field_violations[0].field.as_deref(), Some("row 3"));
+    }
+}
@@ -0,0 +1,60 @@
+use error::{Error, Result};
+
+/// Normalizes a key for comparison by lowercasing it and dropping whitespace and punctuation, so
+/// that e.g. "Part Number", "part_number" and " PART-NUMBER " all match.
+pub fn normalize_key(key: &str) -> String {
+    key.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// Finds the position of the key among the keys, preferring an exact match over a normalized one.
+pub fn position_of_key<K: AsRef<str>>(keys: &[K], key: &str) -> Option<usize> {
+    keys.iter().position(|item| item.as_ref() == key).or_else(|| {
+        let normalized = normalize_key(key);
+        keys.iter().position(|item| normalize_key(item.as_ref()) == normalized)
+    })
+}
+
+/// Like `position_of_key`, but fails with an error listing the available keys if there's no match.
+/// The `description` says what the key is used for, e.g. "level key".
+pub fn find_key<K: AsRef<str>>(keys: &[K], key: &str, description: &str) -> Result<usize> {
+    position_of_key(keys, key).ok_or_else(|| {
+        let available = keys.iter().map(|item| item.as_ref()).collect::<Vec<_>>();
+        Error::invalid_argument(format!(
+            "Couldn't find {description} {key:?} in the flat data keys, available keys are \
+             {available:?}"
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_key, normalize_key, position_of_key};
+    use error::Error;
+
+    #[test]
+    fn normalizes_case_whitespace_and_punctuation() {
+        for key in ["Part Number", "part_number", " PART-NUMBER ", "Part\tNumber."] {
+            assert_eq!(normalize_key(key), "partnumber");
+        }
+    }
+
+    #[test]
+    fn prefers_exact_match() {
+        let keys = ["part number", "Part Number"];
+        assert_eq!(position_of_key(&keys, "Part Number"), Some(1));
+        assert_eq!(position_of_key(&keys, "PART_NUMBER"), Some(0));
+    }
+
+    #[test]
+    fn lists_available_keys() {
+        let message = match find_key(&["Part Number", "Qty"], "level", "level key") {
+            Err(Error::InvalidArgument(payload)) => payload.message,
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(
+            message,
+            "Couldn't find level key \"level\" in the flat data keys, available keys are \
+             [\"Part Number\", \"Qty\"]"
+        );
+    }
+}
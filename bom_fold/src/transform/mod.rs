@@ -3,22 +3,40 @@ mod ordered_level_key;
 mod absolute_parent_locator;
 pub use absolute_parent_locator::*;
 
+mod indentation;
+pub use indentation::*;
+
 mod data;
 pub use data::*;
 
+mod keys;
+pub use keys::*;
+
 mod rules;
 pub use rules::*;
 
+mod unfold;
+pub use unfold::*;
+
 use error::Result;
 
 /// Converts `FlatData` item hierarchy representation into the `FoldedData` representation.
+///
+/// For `ChildIdentificationPolicy::IndentedKey`, the flat data must already have had
+/// [`FlatData::extract_indentation`] applied, which the parsers do automatically.
 pub fn transform<'data>(flat_data: &'data FlatData, rules: &Rules) -> Result<FoldedData<'data>> {
     match rules.child_identification_policy {
         ChildIdentificationPolicy::OrderedLevelKey(ref key) => {
-            ordered_level_key::fold(flat_data, key)
+            ordered_level_key::fold(flat_data, key, rules.level_validation)
+        }
+        ChildIdentificationPolicy::OutlineLevelKey(ref key) => {
+            ordered_level_key::fold_outline(flat_data, key, rules.level_validation)
+        }
+        ChildIdentificationPolicy::IndentedKey(ref indentation) => {
+            ordered_level_key::fold(flat_data, &indentation.level_key, rules.level_validation)
         }
-        ChildIdentificationPolicy::Absolute(_) => {
-            unimplemented!("Currently don't support absolute parent location")
+        ChildIdentificationPolicy::Absolute(ref locator) => {
+            absolute_parent_locator::fold(flat_data, locator)
         }
     }
 }
@@ -1,16 +1,114 @@
-use crate::transform::data::{FlatData, FoldedData, Node, Value};
-use error::{Error, Result};
-use std::cmp::Ordering;
+use crate::transform::{
+    data::{FlatData, FoldedData, Node, Value},
+    keys::find_key,
+    rules::LevelValidation,
+};
+use chrono::NaiveDate;
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
+use std::{cmp::Ordering, ops::Range};
 
 /// Folds the flat data using a parent node key.
-pub fn fold<'data>(flat_data: &'data FlatData, level_key: &str) -> Result<FoldedData<'data>> {
+pub fn fold<'data>(
+    flat_data: &'data FlatData,
+    level_key: &str,
+    validation: LevelValidation,
+) -> Result<FoldedData<'data>> {
+    fold_by(flat_data, level_key, validation, |level| Ok(level.clone()))
+}
+
+/// Folds the flat data using a dotted outline key like "1.2.10", where the depth of an item is the
+/// number of segments in its outline number. Unlike [`fold`], "1.10" is a sibling of "1.9".
+pub fn fold_outline<'data>(
+    flat_data: &'data FlatData,
+    level_key: &str,
+    validation: LevelValidation,
+) -> Result<FoldedData<'data>> {
+    fold_by(flat_data, level_key, validation, |level| OutlineLevel::parse(level).map(|o| o.depth()))
+}
+
+/// Folds the flat data using the levels computed from each record's level key value. Any time the
+/// level increases, all subsequent records are children of the most recent record until the level
+/// returns to the same or a lower value. Each of the `FlatData::parts` is folded on its own, so
+/// their top level items are never children of an item in a previous part.
+fn fold_by<'data, L, F>(
+    flat_data: &'data FlatData,
+    level_key: &str,
+    validation: LevelValidation,
+    to_level: F,
+) -> Result<FoldedData<'data>>
+where
+    L: Depth,
+    F: Fn(&'data Value<'data>) -> std::result::Result<L, String>,
+{
     if flat_data.records.is_empty() || flat_data.keys.is_empty() {
         return Ok(FoldedData::default());
     }
 
-    let level_key_index = flat_data.keys.iter().position(|item| item == level_key);
-    let level_key_index = level_key_index
-        .ok_or_else(|| Error::invalid_argument("Couldn't find level key in the flat data keys"))?;
+    let level_key_index = find_key(&flat_data.keys, level_key, "level key")?;
+
+    let mut field_violations = Vec::new();
+    let levels = flat_data
+        .records
+        .iter()
+        .enumerate()
+        .filter_map(|(index, record)| match to_level(&record[level_key_index]) {
+            Ok(level) => Some(level),
+            Err(description) => {
+                field_violations.push(FieldViolation {
+                    field: Some(flat_data.source(index)),
+                    description: Some(description),
+                });
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    if !field_violations.is_empty() {
+        return Err(Error::invalid_argument_with(
+            format!("Found {} row(s) with invalid {level_key:?} values", field_violations.len()),
+            Some(BadRequest { field_violations }),
+        ));
+    }
+
+    // Levels are compared by their depths, since e.g. `Text("10")` sorts before `Text("9")`.
+    let Depths { depths, top_level } = L::depths(&levels);
+    let mut malformed_levels = Vec::new();
+    let parts = flat_data
+        .parts()
+        .into_iter()
+        .map(|records| {
+            let (depths, malformed) = find_malformed_levels(
+                &depths[records.clone()],
+                top_level,
+                flat_data,
+                records.clone(),
+                level_key_index,
+            );
+            malformed_levels.extend(malformed);
+            (records, depths)
+        })
+        .collect::<Vec<_>>();
+    match validation {
+        LevelValidation::Strict if !malformed_levels.is_empty() => {
+            let field_violations =
+                malformed_levels.into_iter().map(|(violation, _)| violation).collect::<Vec<_>>();
+            return Err(Error::invalid_argument_with(
+                format!("Found {} row(s) with malformed levels", field_violations.len()),
+                Some(BadRequest { field_violations }),
+            ));
+        }
+        _ => {
+            for (violation, handling) in malformed_levels {
+                tracing::warn!(
+                    "{}: {}, {handling}",
+                    violation.field.unwrap_or_default(),
+                    violation.description.unwrap_or_default(),
+                );
+            }
+        }
+    }
 
     // The root nodes in the data if there are multiple top level boms.
     let mut top_level_nodes = Vec::with_capacity(flat_data.records.len());
@@ -19,56 +117,255 @@ pub fn fold<'data>(flat_data: &'data FlatData, level_key: &str) -> Result<Folded
     // parents.
     let mut working_node_stack = Vec::with_capacity(flat_data.records.len());
 
-    for record in flat_data.records.iter() {
-        let current_record_level = &record[level_key_index];
-
-        if !working_node_stack.is_empty() {
-            unwind_working_stack(
-                &mut working_node_stack,
-                &mut top_level_nodes,
-                current_record_level,
-            );
+    for (records, depths) in parts {
+        for (record, current_record_depth) in flat_data.records[records].iter().zip(depths) {
+            if !working_node_stack.is_empty() {
+                unwind_working_stack(
+                    &mut working_node_stack,
+                    &mut top_level_nodes,
+                    current_record_depth,
+                );
+            }
+            working_node_stack.push(DepthNode {
+                depth: current_record_depth,
+                node: Node { attributes: record, children: Vec::new() },
+            });
         }
-        working_node_stack.push(LevelNode {
-            level: current_record_level.clone(),
-            node: Node { attributes: record, children: Vec::new() },
-        });
+        unwind_working_stack_unconditionally(&mut working_node_stack, &mut top_level_nodes);
     }
-    unwind_working_stack_unconditionally(&mut working_node_stack, &mut top_level_nodes);
     Ok(FoldedData { top_level_nodes, attribute_keys: &flat_data.keys })
 }
 
-/// Stores the level alongside the node for convenience.
-struct LevelNode<'a> {
-    level: Value<'a>,
+/// Returns the depth of each record of the part, along with a violation for every record whose
+/// level has no depth, for the first record if it's not at the top level (or the shallowest level
+/// of the part if there is no fixed top level) and for every record that is more than one level
+/// deeper than the record before it, and how the record is folded anyway in lenient mode. A record
+/// without a depth gets the depth of the record before it.
+fn find_malformed_levels(
+    depths: &[std::result::Result<i64, String>],
+    top_level: Option<i64>,
+    flat_data: &FlatData,
+    records: Range<usize>,
+    level_key_index: usize,
+) -> (Vec<i64>, Vec<(FieldViolation, &'static str)>) {
+    let level_values =
+        flat_data.records[records.clone()].iter().map(|record| &record[level_key_index]);
+    let root_depth =
+        top_level.unwrap_or_else(|| depths.iter().flatten().copied().min().unwrap_or_default());
+    let mut previous: Option<(i64, &Value)> = None;
+    let mut resolved_depths = Vec::with_capacity(depths.len());
+    let mut field_violations = Vec::new();
+    for (index, (depth, value)) in records.zip(depths.iter().zip(level_values)) {
+        let (depth, malformed) = match (depth, previous) {
+            (Err(description), None) => {
+                (root_depth, Some((description.clone(), "making it a top level item")))
+            }
+            (Err(description), Some((previous_depth, _))) => (
+                previous_depth,
+                Some((description.clone(), "making it a sibling of the previous row")),
+            ),
+            // There is no item before the first one to attach it to.
+            (Ok(depth), None) if *depth != root_depth => (
+                *depth,
+                Some((
+                    format!("First row has level {value} but is not a top level item"),
+                    "making it a top level item",
+                )),
+            ),
+            (Ok(depth), Some((previous_depth, previous_value))) if *depth > previous_depth + 1 => (
+                *depth,
+                Some((
+                    format!(
+                        "Level {value} skips one or more levels below the previous row's level \
+                         {previous_value}"
+                    ),
+                    "attaching it to the closest preceding shallower item",
+                )),
+            ),
+            (Ok(depth), _) => (*depth, None),
+        };
+        if let Some((description, handling)) = malformed {
+            field_violations.push((
+                FieldViolation {
+                    field: Some(flat_data.source(index)),
+                    description: Some(description),
+                },
+                handling,
+            ));
+        }
+        resolved_depths.push(depth);
+        previous = Some((depth, value));
+    }
+    (resolved_depths, field_violations)
+}
+
+/// Depths of the levels of all records.
+struct Depths {
+    /// Depth of each record in order, or a description of why its level has no depth.
+    depths: Vec<std::result::Result<i64, String>>,
+    /// Depth of top level items, or `None` if the depths only rank the levels, in which case the
+    /// shallowest level of each part is its top level.
+    top_level: Option<i64>,
+}
+
+/// Levels that can be converted to integer depths for validation and folding.
+trait Depth: Sized {
+    /// Converts the levels of all records to depths, preserving their order.
+    fn depths(levels: &[Self]) -> Depths;
+}
+
+impl Depth for usize {
+    /// Outline depths, where top level items have depth 1.
+    fn depths(levels: &[Self]) -> Depths {
+        Depths {
+            depths: levels.iter().map(|level| Ok(*level as i64)).collect(),
+            top_level: Some(1),
+        }
+    }
+}
+
+impl Depth for Value<'_> {
+    /// Integer levels (e.g. `Number(3.0)` or "3") are their own depth, so that a level that is
+    /// skipped everywhere is still detected, with the top level at 1, or 0 if the levels start at
+    /// 0. Other levels, e.g. "1.1" or `Number(1.5)`, are ranked amongst the distinct levels in the
+    /// data. Blank levels and levels that can't be compared with the others, e.g. a date amongst
+    /// numbers, never have a depth.
+    fn depths(levels: &[Self]) -> Depths {
+        // Text levels are only compared as numbers if all of them are numbers.
+        let numeric_text = levels
+            .iter()
+            .filter(|level| matches!(level, Value::Text(_)) && !level.is_empty())
+            .all(|level| crate::analyze::number(level).is_some());
+        let keys = levels
+            .iter()
+            .map(|level| match level {
+                _ if level.is_empty() => None,
+                Value::Text(text) if !numeric_text => Some(LevelKey::Text(text.trim())),
+                Value::Boolean(value) => Some(LevelKey::Boolean(*value)),
+                Value::Date(date) => Some(LevelKey::Date(*date)),
+                level => crate::analyze::number(level)
+                    .filter(|number| !number.is_nan())
+                    .map(LevelKey::Number),
+            })
+            .collect::<Vec<_>>();
+
+        // Levels of a different kind than most levels can't be compared with them, and ties go to
+        // the kind that appears first.
+        let mut kinds: Vec<(std::mem::Discriminant<LevelKey>, usize)> = Vec::new();
+        for key in keys.iter().flatten() {
+            let kind = std::mem::discriminant(key);
+            match kinds.iter_mut().find(|(other, _)| *other == kind) {
+                Some((_, count)) => *count += 1,
+                None => kinds.push((kind, 1)),
+            }
+        }
+        let kind = kinds.iter().rev().max_by_key(|(_, count)| *count).map(|(kind, _)| *kind);
+        let keys = keys
+            .into_iter()
+            .zip(levels)
+            .map(|(key, level)| match key {
+                Some(key) if Some(std::mem::discriminant(&key)) == kind => Ok(key),
+                _ if level.is_empty() => Err("Level is blank".to_string()),
+                _ => Err(format!("Level {level} can't be compared with the other levels")),
+            })
+            .collect::<Vec<_>>();
+
+        let integers = keys
+            .iter()
+            .map(|key| match key {
+                Ok(LevelKey::Number(n)) if n.fract() == 0.0 => Some(Ok(*n as i64)),
+                Ok(_) => None,
+                Err(description) => Some(Err(description.clone())),
+            })
+            .collect::<Option<Vec<_>>>();
+        if let Some(depths) = integers {
+            if let Some(shallowest) = depths.iter().flatten().copied().min() {
+                return Depths { depths, top_level: Some(shallowest.min(1)) };
+            }
+        }
+
+        let mut distinct = keys.iter().flatten().collect::<Vec<_>>();
+        distinct.sort_by(|lhs, rhs| lhs.partial_cmp(rhs).unwrap_or(Ordering::Equal));
+        distinct.dedup();
+        let depths = keys
+            .iter()
+            .map(|key| {
+                let key = key.as_ref().map_err(Clone::clone)?;
+                Ok(distinct.iter().position(|distinct| *distinct == key).unwrap_or_default() as i64)
+            })
+            .collect();
+        Depths { depths, top_level: None }
+    }
+}
+
+/// Comparable form of a non-blank level, where only levels of the same kind can be compared.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum LevelKey<'a> {
+    Number(f64),
+    Text(&'a str),
+    Boolean(bool),
+    Date(NaiveDate),
+}
+
+/// Dotted outline number, e.g. "1.2.10" is parsed as `[1, 2, 10]`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OutlineLevel {
+    pub segments: Vec<u64>,
+}
+
+impl OutlineLevel {
+    /// Parses an outline number from text like "1.2.10" (a single trailing "." is allowed).
+    ///
+    /// Numbers are parsed from their shortest representation, so `Number(1.1)` is "1.1". Since
+    /// `Number(1.10)` is also "1.1", outline columns should be mapped to `ValueType::Text`.
+    pub fn parse(value: &Value) -> std::result::Result<Self, String> {
+        let text = value.to_string();
+        let trimmed = text.trim();
+        let trimmed = trimmed.strip_suffix('.').unwrap_or(trimmed);
+        let segments = trimmed
+            .split('.')
+            .map(|segment| segment.trim().parse::<u64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| format!("{text:?} is not a dotted outline number: {e}"))?;
+        Ok(Self { segments })
+    }
+
+    /// Depth of the item in the hierarchy, where top level items have depth 1.
+    pub fn depth(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+/// Stores the depth alongside the node for convenience.
+struct DepthNode<'a> {
+    depth: i64,
     node: Node<'a>,
 }
 
 /// Pops the working stack for all of the "done" nodes (we know there are no more children because
 /// we're now going back up in levels).
 fn unwind_working_stack<'a>(
-    working_node_stack: &mut Vec<LevelNode<'a>>,
+    working_node_stack: &mut Vec<DepthNode<'a>>,
     top_level_nodes: &mut Vec<Node<'a>>,
-    current_record_level: &Value,
+    current_record_depth: i64,
 ) {
     loop {
         // Fetch current working node and also check/return if the list is empty.
-        let working_node_level = match working_node_stack.last() {
-            Some(node) => &node.level,
+        let working_node_depth = match working_node_stack.last() {
+            Some(node) => node.depth,
             None => return,
         };
         // Finalize the current working node if its not the parent of the next node.
-        match working_node_level.partial_cmp(current_record_level) {
-            Some(Ordering::Greater) | Some(Ordering::Equal) => {
-                finalize_working_node(working_node_stack, top_level_nodes)
-            }
-            _ => return,
+        if working_node_depth >= current_record_depth {
+            finalize_working_node(working_node_stack, top_level_nodes)
+        } else {
+            return;
         }
     }
 }
 
 fn unwind_working_stack_unconditionally<'a>(
-    working_node_stack: &mut Vec<LevelNode<'a>>,
+    working_node_stack: &mut Vec<DepthNode<'a>>,
     top_level_nodes: &mut Vec<Node<'a>>,
 ) {
     while !working_node_stack.is_empty() {
@@ -77,7 +374,7 @@ fn unwind_working_stack_unconditionally<'a>(
 }
 
 fn finalize_working_node<'a>(
-    working_node_stack: &mut Vec<LevelNode<'a>>,
+    working_node_stack: &mut Vec<DepthNode<'a>>,
     top_level_nodes: &mut Vec<Node<'a>>,
 ) {
     let popped = match working_node_stack.pop() {
@@ -92,12 +389,18 @@ fn finalize_working_node<'a>(
 
 #[cfg(test)]
 mod tests {
-    use crate::transform::{FlatData, FoldedData, Node, Value};
+    use crate::transform::{FlatData, FoldedData, LevelValidation, Node, Value};
+    use chrono::NaiveDate;
+    use error::Error;
     use pretty_assertions::assert_eq;
     use std::borrow::Cow;
 
     fn test_case(key: &str, input: &FlatData, output: &FoldedData) {
-        assert_eq!(&super::fold(&input, key).unwrap(), output)
+        assert_eq!(&super::fold(input, key, LevelValidation::Strict).unwrap(), output)
+    }
+
+    fn outline_test_case(key: &str, input: &FlatData, output: &FoldedData) {
+        assert_eq!(&super::fold_outline(input, key, LevelValidation::Strict).unwrap(), output)
     }
 
     fn keys() -> Vec<Cow<'static, str>> {
@@ -106,9 +409,13 @@ mod tests {
 
     #[test]
     fn degenerate() {
-        let input =
-            FlatData { keys: keys(), records: vec![vec![Value::text("1"), Value::text("foo")]] };
+        let input = FlatData {
+            keys: keys(),
+            records: vec![vec![Value::text("1"), Value::text("foo")]],
+            ..Default::default()
+        };
         let output = FoldedData {
+            attribute_keys: &input.keys,
             top_level_nodes: vec![Node { attributes: &input.records[0], children: Vec::new() }],
         };
         test_case("level", &input, &output);
@@ -122,8 +429,10 @@ mod tests {
                 vec![Value::text("1"), Value::text("foo")],
                 vec![Value::text("1.1"), Value::text("foo")],
             ],
+            ..Default::default()
         };
         let output = FoldedData {
+            attribute_keys: &input.keys,
             top_level_nodes: vec![Node {
                 attributes: &input.records[0],
                 children: vec![Node { attributes: &input.records[1], children: Vec::new() }],
@@ -140,8 +449,10 @@ mod tests {
                 vec![Value::Number(1.0), Value::text("foo")],
                 vec![Value::Number(1.1), Value::text("foo")],
             ],
+            ..Default::default()
         };
         let output = FoldedData {
+            attribute_keys: &input.keys,
             top_level_nodes: vec![Node {
                 attributes: &input.records[0],
                 children: vec![Node { attributes: &input.records[1], children: Vec::new() }],
@@ -160,8 +471,10 @@ mod tests {
                 vec![Value::text("1"), Value::text("3")],
                 vec![Value::text("1.1"), Value::text("4")],
             ],
+            ..Default::default()
         };
         let output = FoldedData {
+            attribute_keys: &input.keys,
             top_level_nodes: vec![
                 Node {
                     attributes: &input.records[0],
@@ -187,8 +500,10 @@ mod tests {
                 vec![Value::text("1.2"), Value::text("3")],
                 vec![Value::text("1.1"), Value::text("4")],
             ],
+            ..Default::default()
         };
         let output = FoldedData {
+            attribute_keys: &input.keys,
             top_level_nodes: vec![Node {
                 attributes: &input.records[0],
                 children: vec![
@@ -206,4 +521,362 @@ mod tests {
         };
         test_case("level", &input, &output);
     }
+
+    #[test]
+    fn outline_double_digit_siblings() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("1"), Value::text("0")],
+                vec![Value::text("1.9"), Value::text("1")],
+                vec![Value::text("1.9.1"), Value::text("2")],
+                vec![Value::text("1.10"), Value::text("3")],
+                vec![Value::text("1.10.1"), Value::text("4")],
+                vec![Value::text("1.10.2"), Value::text("5")],
+                vec![Value::text("1.11"), Value::text("6")],
+                vec![Value::text("2"), Value::text("7")],
+            ],
+            ..Default::default()
+        };
+        let output = FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![
+                Node {
+                    attributes: &input.records[0],
+                    children: vec![
+                        Node {
+                            attributes: &input.records[1],
+                            children: vec![Node {
+                                attributes: &input.records[2],
+                                children: Vec::new(),
+                            }],
+                        },
+                        Node {
+                            attributes: &input.records[3],
+                            children: vec![
+                                Node { attributes: &input.records[4], children: Vec::new() },
+                                Node { attributes: &input.records[5], children: Vec::new() },
+                            ],
+                        },
+                        Node { attributes: &input.records[6], children: Vec::new() },
+                    ],
+                },
+                Node { attributes: &input.records[7], children: Vec::new() },
+            ],
+        };
+        outline_test_case("level", &input, &output);
+    }
+
+    #[test]
+    fn outline_number_levels() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::Number(1.0), Value::text("foo")],
+                vec![Value::Number(1.1), Value::text("foo")],
+                vec![Value::Number(1.2), Value::text("foo")],
+            ],
+            ..Default::default()
+        };
+        let output = FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![Node {
+                attributes: &input.records[0],
+                children: vec![
+                    Node { attributes: &input.records[1], children: Vec::new() },
+                    Node { attributes: &input.records[2], children: Vec::new() },
+                ],
+            }],
+        };
+        outline_test_case("level", &input, &output);
+    }
+
+    #[test]
+    fn parts_are_folded_on_their_own() {
+        // The second part starts one level deeper than the first one ended, which is reported
+        // since it doesn't start at the top level.
+        let input = FlatData {
+            keys: keys(),
+            records: [1.0, 2.0, 3.0, 4.0]
+                .into_iter()
+                .map(|level| vec![Value::Number(level), Value::text("foo")])
+                .collect(),
+            part_starts: vec![0, 2],
+            ..Default::default()
+        };
+        let output = FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![
+                Node {
+                    attributes: &input.records[0],
+                    children: vec![Node { attributes: &input.records[1], children: Vec::new() }],
+                },
+                Node {
+                    attributes: &input.records[2],
+                    children: vec![Node { attributes: &input.records[3], children: Vec::new() }],
+                },
+            ],
+        };
+        assert_eq!(super::fold(&input, "level", LevelValidation::Lenient).unwrap(), output);
+        assert_eq!(malformed_rows(&input, super::fold), vec!["row 3"]);
+    }
+
+    #[test]
+    fn text_levels_compare_numerically() {
+        let input = FlatData {
+            keys: keys(),
+            records: ["8", "9", "10", "9"]
+                .into_iter()
+                .map(|level| vec![Value::text(level), Value::text("foo")])
+                .collect(),
+            ..Default::default()
+        };
+        let output = FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![Node {
+                attributes: &input.records[0],
+                children: vec![
+                    Node {
+                        attributes: &input.records[1],
+                        children: vec![Node {
+                            attributes: &input.records[2],
+                            children: Vec::new(),
+                        }],
+                    },
+                    Node { attributes: &input.records[3], children: Vec::new() },
+                ],
+            }],
+        };
+        // Lenient, since the first row isn't at the top level.
+        assert_eq!(super::fold(&input, "level", LevelValidation::Lenient).unwrap(), output);
+    }
+
+    #[test]
+    fn outline_parse() {
+        let parse = |text| super::OutlineLevel::parse(&Value::text(text)).map(|o| o.segments);
+        assert_eq!(parse("1.2.10"), Ok(vec![1, 2, 10]));
+        assert_eq!(parse(" 3. "), Ok(vec![3]));
+        assert!(parse("1..2").is_err());
+        assert!(parse("A.1").is_err());
+    }
+
+    #[test]
+    fn outline_invalid_level() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("1"), Value::text("foo")],
+                vec![Value::text("1.x"), Value::text("foo")],
+            ],
+            ..Default::default()
+        };
+        let payload = match super::fold_outline(&input, "level", LevelValidation::Strict) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        assert_eq!(payload.field_violations[0].field.as_deref(), Some("row 2"));
+    }
+
+    /// Signature shared by the level based folding functions.
+    type Fold =
+        for<'data> fn(&'data FlatData, &str, LevelValidation) -> error::Result<FoldedData<'data>>;
+
+    fn malformed_rows(input: &FlatData, fold: Fold) -> Vec<String> {
+        let payload = match fold(input, "level", LevelValidation::Strict) {
+            Err(Error::InvalidArgument(payload)) => payload.payload.unwrap(),
+            other => panic!("Expected invalid argument, got {other:?}"),
+        };
+        payload.field_violations.into_iter().map(|violation| violation.field.unwrap()).collect()
+    }
+
+    #[test]
+    fn strict_rejects_skipped_levels() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::Number(1.0), Value::text("0")],
+                vec![Value::Number(4.0), Value::text("1")],
+                vec![Value::Number(2.0), Value::text("2")],
+                vec![Value::Number(3.0), Value::text("3")],
+                vec![Value::Number(1.0), Value::text("4")],
+                vec![Value::Number(3.0), Value::text("5")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(malformed_rows(&input, super::fold), vec!["row 2", "row 6"]);
+    }
+
+    #[test]
+    fn strict_rejects_non_root_first_row() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("1.1"), Value::text("0")],
+                vec![Value::text("1"), Value::text("1")],
+                vec![Value::text("1.1"), Value::text("2")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(malformed_rows(&input, super::fold), vec!["row 1"]);
+
+        // Integer levels start at 1, even if no row is at that level.
+        let input = FlatData {
+            keys: keys(),
+            records: [3, 4, 3]
+                .into_iter()
+                .map(|level| vec![Value::Integer(level), Value::text("foo")])
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(malformed_rows(&input, super::fold), vec!["row 1"]);
+
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("1"), Value::text("0")],
+                vec![Value::text("1.1.1"), Value::text("1")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(malformed_rows(&input, super::fold_outline), vec!["row 2"]);
+    }
+
+    #[test]
+    fn describes_lenient_handling() {
+        let input = FlatData {
+            keys: keys(),
+            records: [2.0, 1.0, 3.0]
+                .into_iter()
+                .map(|level| vec![Value::Number(level), Value::text("")])
+                .collect(),
+            ..Default::default()
+        };
+        let handling = super::find_malformed_levels(&[Ok(2), Ok(1), Ok(3)], None, &input, 0..3, 0)
+            .1
+            .into_iter()
+            .map(|(_, handling)| handling)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            handling,
+            ["making it a top level item", "attaching it to the closest preceding shallower item"]
+        );
+    }
+
+    /// Runs the lenient handling of the levels in the first column, returning the resolved depths
+    /// and the description and handling of each malformed level.
+    fn resolve_levels(input: &FlatData) -> (Vec<i64>, Vec<(String, &'static str)>) {
+        let levels = input.records.iter().map(|record| record[0].clone()).collect::<Vec<_>>();
+        let super::Depths { depths, top_level } = <Value as super::Depth>::depths(&levels);
+        let records = 0..input.records.len();
+        let (depths, malformed) =
+            super::find_malformed_levels(&depths, top_level, input, records, 0);
+        let descriptions = malformed
+            .into_iter()
+            .map(|(violation, handling)| (violation.description.unwrap(), handling))
+            .collect();
+        (depths, descriptions)
+    }
+
+    #[test]
+    fn reports_blank_levels() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::Integer(1), Value::text("0")],
+                vec![Value::Integer(2), Value::text("1")],
+                vec![Value::Empty, Value::text("2")],
+                vec![Value::text(" "), Value::text("3")],
+                vec![Value::Integer(3), Value::text("4")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(malformed_rows(&input, super::fold), vec!["row 3", "row 4"]);
+
+        // Rows without a level become siblings of the previous row.
+        let (depths, descriptions) = resolve_levels(&input);
+        assert_eq!(depths, [1, 2, 2, 2, 3]);
+        assert_eq!(
+            descriptions,
+            [
+                ("Level is blank".to_string(), "making it a sibling of the previous row"),
+                ("Level is blank".to_string(), "making it a sibling of the previous row"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_mixed_levels() {
+        // Text and numbers are compared as numbers as long as all text levels are numbers.
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::Number(1.0), Value::text("0")],
+                vec![Value::text("1.5"), Value::text("1")],
+                vec![Value::Date(NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()), Value::text("2")],
+                vec![Value::Number(1.5), Value::text("3")],
+                vec![Value::Integer(2), Value::text("4")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(malformed_rows(&input, super::fold), vec!["row 3"]);
+        let (depths, descriptions) = resolve_levels(&input);
+        assert_eq!(depths, [0, 1, 1, 1, 2]);
+        assert_eq!(
+            descriptions,
+            [(
+                "Level 2024-01-31 can't be compared with the other levels".to_string(),
+                "making it a sibling of the previous row"
+            )]
+        );
+
+        // Otherwise the text levels are compared as text, and numbers can't be compared with them.
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::text("1"), Value::text("0")],
+                vec![Value::text("1.1"), Value::text("1")],
+                vec![Value::text("1.1.1"), Value::text("2")],
+                vec![Value::Number(2.0), Value::text("3")],
+            ],
+            ..Default::default()
+        };
+        assert_eq!(malformed_rows(&input, super::fold), vec!["row 4"]);
+        let (depths, descriptions) = resolve_levels(&input);
+        assert_eq!(depths, [0, 1, 2, 2]);
+        assert_eq!(
+            descriptions,
+            [(
+                "Level 2 can't be compared with the other levels".to_string(),
+                "making it a sibling of the previous row"
+            )]
+        );
+    }
+
+    #[test]
+    fn lenient_reparents_skipped_levels() {
+        let input = FlatData {
+            keys: keys(),
+            records: vec![
+                vec![Value::Number(3.0), Value::text("0")],
+                vec![Value::Number(1.0), Value::text("1")],
+                vec![Value::Number(4.0), Value::text("2")],
+                vec![Value::Number(2.0), Value::text("3")],
+            ],
+            ..Default::default()
+        };
+        let output = FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![
+                Node { attributes: &input.records[0], children: Vec::new() },
+                Node {
+                    attributes: &input.records[1],
+                    children: vec![
+                        Node { attributes: &input.records[2], children: Vec::new() },
+                        Node { attributes: &input.records[3], children: Vec::new() },
+                    ],
+                },
+            ],
+        };
+        assert_eq!(super::fold(&input, "level", LevelValidation::Lenient).unwrap(), output);
+    }
 }
@@ -1,31 +1,517 @@
-use crate::transform::{absolute_parent_locator::AbsoluteParentLocator, data::ValueType};
+use crate::transform::{
+    absolute_parent_locator::AbsoluteParentLocator, data::ValueType, indentation::IndentationRules,
+    keys::normalize_key,
+};
+use error::{
+    error_details::{bad_request::FieldViolation, BadRequest},
+    Error, Result,
+};
 use serde::Deserialize;
 use std::collections::HashMap;
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Rules {
     pub type_mapping: Option<HashMap<String, ValueType>>,
+    /// How the numbers of each key are written, for inputs that don't use "1000.5", e.g.
+    /// `{"Quantity": {"decimal_separator": ",", "thousands_separator": "."}}` for "1.000,5".
+    pub number_formats: Option<HashMap<String, NumberFormat>>,
+    /// Alternative header names for the keys used in these rules, e.g. `{"Part Number": ["PN",
+    /// "Part No."]}`. Headers are matched against keys and aliases ignoring case, whitespace and
+    /// punctuation, and the parsers rename matching headers to the key.
+    pub header_aliases: Option<HashMap<String, Vec<String>>>,
+    /// Determines which row holds the headers. Rows above it are ignored.
+    #[serde(default)]
+    pub header_row: HeaderRow,
+    /// Whether to drop trailing rows that are missing any of the `item_keys`, like a totals row or
+    /// an export footer.
+    #[serde(default = "Rules::default_drop_footer_rows")]
+    pub drop_footer_rows: bool,
+    /// Determines which worksheets are read from workbook input. Ignored for CSV input.
+    #[serde(default)]
+    pub sheet_selection: SheetSelection,
     pub child_identification_policy: ChildIdentificationPolicy,
+    /// Determines how malformed level hierarchies are handled by the level based policies.
+    #[serde(default)]
+    pub level_validation: LevelValidation,
+    /// If set, items listed several times under the same parent are merged into one before the
+    /// output is formatted.
+    #[serde(default)]
+    pub consolidation: Option<ConsolidationRules>,
+    /// Numeric attributes, like cost or weight, whose extended values are rolled up the hierarchy
+    /// into new attributes.
+    #[serde(default)]
+    pub rollups: Vec<RollupRules>,
     /// Determines the format for the output.
     pub output_rules: OutputRules,
 }
 
+impl Rules {
+    fn default_drop_footer_rows() -> bool {
+        true
+    }
+
+    /// Keys that every item must have a value for.
+    pub fn item_keys(&self) -> Vec<&str> {
+        let mut keys = match self.child_identification_policy {
+            ChildIdentificationPolicy::OrderedLevelKey(ref key)
+            | ChildIdentificationPolicy::OutlineLevelKey(ref key) => vec![key.as_str()],
+            ChildIdentificationPolicy::IndentedKey(ref indentation) => {
+                vec![indentation.key.as_str()]
+            }
+            ChildIdentificationPolicy::Absolute(ref locator) => vec![locator.id_key.as_str()],
+        };
+        match self.output_rules {
+            OutputRules::ItemSync(ref item_sync) => keys.push(item_sync.id_key.as_str()),
+        }
+        keys.dedup();
+        keys
+    }
+
+    /// Keys that must be present in the headers of the input.
+    pub fn required_keys(&self) -> Vec<&str> {
+        let mut keys = self.item_keys();
+        if let ChildIdentificationPolicy::Absolute(ref locator) = self.child_identification_policy {
+            keys.push(locator.parent_key.as_str());
+        }
+        keys
+    }
+
+    /// Key of the attribute that places an item in the hierarchy, i.e. its level or parent, which
+    /// says nothing about the item itself once folded.
+    pub fn position_key(&self) -> &str {
+        match self.child_identification_policy {
+            ChildIdentificationPolicy::OrderedLevelKey(ref key)
+            | ChildIdentificationPolicy::OutlineLevelKey(ref key) => key,
+            ChildIdentificationPolicy::IndentedKey(ref indentation) => &indentation.level_key,
+            ChildIdentificationPolicy::Absolute(ref locator) => &locator.parent_key,
+        }
+    }
+
+    /// Type the values of the key are parsed as: the `type_mapping` of the key, or `Number` for the
+    /// keys of the `number_formats`.
+    pub fn value_type(&self, key: &str) -> Option<&ValueType> {
+        match self.type_mapping.as_ref().and_then(|map| map.get(key)) {
+            Some(value_type) => Some(value_type),
+            None if self.number_format(key).is_some() => Some(&ValueType::Number),
+            None => None,
+        }
+    }
+
+    /// Format the numbers of the key are written in, if it isn't the standard one.
+    pub fn number_format(&self, key: &str) -> Option<&NumberFormat> {
+        self.number_formats.as_ref().and_then(|map| map.get(key))
+    }
+
+    /// Every key referenced by these rules.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        match self.child_identification_policy {
+            ChildIdentificationPolicy::OrderedLevelKey(ref key)
+            | ChildIdentificationPolicy::OutlineLevelKey(ref key) => keys.push(key.as_str()),
+            ChildIdentificationPolicy::IndentedKey(ref indentation) => {
+                keys.extend([indentation.key.as_str(), indentation.level_key.as_str()])
+            }
+            ChildIdentificationPolicy::Absolute(ref locator) => {
+                keys.extend([locator.id_key.as_str(), locator.parent_key.as_str()])
+            }
+        }
+        match self.output_rules {
+            OutputRules::ItemSync(ref item_sync) => {
+                keys.push(item_sync.id_key.as_str());
+                keys.extend(item_sync.name_key.as_deref());
+                keys.extend(item_sync.quantity_key.as_deref());
+            }
+        }
+        keys.extend(
+            self.consolidation.iter().flat_map(|c| c.concatenated_keys.iter().map(String::as_str)),
+        );
+        for rollup in self.rollups.iter() {
+            keys.push(rollup.key.as_str());
+            keys.extend(rollup.quantity_key.as_deref());
+        }
+        keys.extend(self.type_mapping.iter().flat_map(|map| map.keys().map(String::as_str)));
+        keys.extend(self.number_formats.iter().flat_map(|map| map.keys().map(String::as_str)));
+        keys.extend(self.header_aliases.iter().flat_map(|map| map.keys().map(String::as_str)));
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    /// Finds the key referenced by these rules that matches the header, preferring an exact match
+    /// over a normalized one.
+    pub fn matching_key(&self, header: &str) -> Option<&str> {
+        let keys = self.keys();
+        if let Some(key) = keys.iter().find(|key| **key == header) {
+            return Some(key);
+        }
+        let normalized = normalize_key(header);
+        keys.into_iter().find(|key| normalize_key(key) == normalized)
+    }
+
+    /// Finds the key that has the header as one of its `header_aliases`.
+    pub fn aliased_key(&self, header: &str) -> Option<&str> {
+        let normalized = normalize_key(header);
+        self.header_aliases.as_ref()?.iter().find_map(|(key, aliases)| {
+            aliases.iter().any(|alias| normalize_key(alias) == normalized).then_some(key.as_str())
+        })
+    }
+
+    /// Checks the rules for values that deserialize fine but can never work, e.g. empty keys.
+    /// Returns an error with a violation for each bad field.
+    pub fn validate(&self) -> Result<()> {
+        let mut field_violations = Vec::new();
+        let mut check = |field: &str, problem: Option<&str>| {
+            if let Some(description) = problem {
+                field_violations.push(FieldViolation {
+                    field: Some(field.to_string()),
+                    description: Some(description.to_string()),
+                })
+            }
+        };
+        let empty = |key: &str| key.trim().is_empty().then_some("must not be empty");
+
+        if let SheetSelection::Name(ref name) = self.sheet_selection {
+            check("sheet_selection.Name", empty(name));
+        }
+        match self.child_identification_policy {
+            ChildIdentificationPolicy::OrderedLevelKey(ref key) => {
+                check("child_identification_policy.OrderedLevelKey", empty(key))
+            }
+            ChildIdentificationPolicy::OutlineLevelKey(ref key) => {
+                check("child_identification_policy.OutlineLevelKey", empty(key))
+            }
+            ChildIdentificationPolicy::IndentedKey(ref indentation) => {
+                check("child_identification_policy.IndentedKey.key", empty(&indentation.key));
+                check(
+                    "child_identification_policy.IndentedKey.level_key",
+                    empty(&indentation.level_key),
+                );
+                check(
+                    "child_identification_policy.IndentedKey.indent_chars",
+                    indentation.indent_chars.is_empty().then_some("must not be empty"),
+                );
+                check(
+                    "child_identification_policy.IndentedKey.indent_width",
+                    (indentation.indent_width == 0).then_some("must be at least 1"),
+                );
+            }
+            ChildIdentificationPolicy::Absolute(ref locator) => {
+                check("child_identification_policy.Absolute.id_key", empty(&locator.id_key));
+                check(
+                    "child_identification_policy.Absolute.parent_key",
+                    empty(&locator.parent_key).or_else(|| {
+                        (locator.id_key == locator.parent_key)
+                            .then_some("must be different from id_key")
+                    }),
+                );
+            }
+        }
+        let mut aliased_keys: HashMap<String, &str> = HashMap::new();
+        for (key, aliases) in self.header_aliases.iter().flatten() {
+            for alias in aliases {
+                let field = format!("header_aliases.{key}");
+                let normalized = normalize_key(alias);
+                if normalized.is_empty() {
+                    check(&field, Some("aliases must contain letters or digits"));
+                } else if let Some(other) = aliased_keys.insert(normalized, key) {
+                    if other != key {
+                        check(
+                            &field,
+                            Some(&format!("alias {alias:?} is also an alias of {other:?}")),
+                        );
+                    }
+                }
+            }
+        }
+        for (index, key) in
+            self.consolidation.iter().flat_map(|c| c.concatenated_keys.iter()).enumerate()
+        {
+            check(&format!("consolidation.concatenated_keys[{index}]"), empty(key));
+        }
+        for (index, rollup) in self.rollups.iter().enumerate() {
+            check(&format!("rollups[{index}].key"), empty(&rollup.key));
+            check(
+                &format!("rollups[{index}].output_key"),
+                rollup.output_key.as_deref().and_then(empty),
+            );
+            check(
+                &format!("rollups[{index}].quantity_key"),
+                rollup.quantity_key.as_deref().and_then(empty),
+            );
+        }
+        for (key, number_format) in self.number_formats.iter().flatten() {
+            let is_reserved = |c: char| c.is_ascii_digit() || matches!(c, '+' | '-');
+            check(
+                &format!("number_formats.{key}.decimal_separator"),
+                is_reserved(number_format.decimal_separator)
+                    .then_some("must not be a digit or sign"),
+            );
+            check(
+                &format!("number_formats.{key}.thousands_separator"),
+                number_format.thousands_separator.and_then(|separator| {
+                    if is_reserved(separator) {
+                        Some("must not be a digit or sign")
+                    } else if separator == number_format.decimal_separator {
+                        Some("must be different from decimal_separator")
+                    } else {
+                        None
+                    }
+                }),
+            );
+            for (index, suffix) in number_format.suffixes.iter().enumerate() {
+                check(&format!("number_formats.{key}.suffixes[{index}]"), empty(suffix));
+            }
+        }
+        match self.output_rules {
+            OutputRules::ItemSync(ref item_sync) => {
+                check("output_rules.ItemSync.id_key", empty(&item_sync.id_key));
+                check(
+                    "output_rules.ItemSync.name_key",
+                    item_sync.name_key.as_deref().and_then(empty),
+                );
+                check(
+                    "output_rules.ItemSync.quantity_key",
+                    item_sync.quantity_key.as_deref().and_then(empty),
+                );
+            }
+        }
+
+        if field_violations.is_empty() {
+            return Ok(());
+        }
+        Err(Error::invalid_argument_with(
+            format!("Found {} invalid field(s) in the rules", field_violations.len()),
+            Some(BadRequest { field_violations }),
+        ))
+    }
+}
+
+/// Locates the header row in the input. Exports often start with a title block, an export
+/// timestamp or blank rows before the actual headers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HeaderRow {
+    /// The first row that contains all of the `required_keys` (or their aliases) among the first
+    /// 100 rows, falling back to the first row if there is none.
+    #[default]
+    Detect,
+    /// The row at this zero-based position, e.g. `Index(2)` for row 3 of a worksheet or line 3 of
+    /// a CSV file, counting blank rows and lines. Positions past the end of the input are rejected
+    /// with an `OutOfRange` error.
+    Index(usize),
+}
+
+/// Selects the worksheet(s) that are read from a workbook.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub enum SheetSelection {
+    /// The first worksheet in the workbook.
+    #[default]
+    First,
+    /// The worksheet with exactly this name, e.g. "Indented BOM".
+    Name(String),
+    /// The worksheet at this zero-based position in the workbook.
+    Index(usize),
+    /// Every worksheet, concatenated in workbook order with columns matched by header name. Each
+    /// worksheet is folded on its own, so items never become children of an item in a previous
+    /// worksheet and the top level items of every worksheet are top level nodes of the output.
+    All,
+}
+
 /// There are two ways we can identify children:
 /// 1. There's some level key that indicates BOM depth. Any time the bom depth level increases, it
-///    indicates that all subsequent elements are children of the most recent parent.
+///    indicates that all subsequent elements are children of the most recent parent. The level is
+///    either compared by value (`OrderedLevelKey`) or is a dotted outline number like "1.2.10"
+///    whose depth is its number of segments (`OutlineLevelKey`). CAD exports often encode the level
+///    as the indentation of an attribute instead (`IndentedKey`).
 /// 2. We can do an exact lookup in the list to find the parent based on some key comparison.
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 pub enum ChildIdentificationPolicy {
     OrderedLevelKey(String),
+    OutlineLevelKey(String),
+    IndentedKey(IndentationRules),
     Absolute(AbsoluteParentLocator),
 }
 
-#[derive(Deserialize)]
+/// Level based hierarchies are malformed if the first item isn't a top level item, an item is more
+/// than one level deeper than the item before it (e.g. level 1 followed by level 4), or an item's
+/// level is blank or can't be compared with the other levels (e.g. a date amongst numbers). Integer
+/// levels are top level items at level 1, or 0 if the levels start at 0, while other levels (e.g.
+/// "1.1") are ranked, with the shallowest one as the top level.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum LevelValidation {
+    /// Malformed items are attached to the closest preceding item with a lower level (or become
+    /// top level items if there is none), items without a valid level become siblings of the item
+    /// before them, and a warning is logged for each.
+    #[default]
+    Lenient,
+    /// Malformed hierarchies are rejected with an error listing every malformed item.
+    Strict,
+}
+
+/// Determines how items listed several times under the same parent, e.g. split by reference
+/// designator, are merged. Items are matched by the ItemSync `id_key` and their quantities are
+/// summed.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsolidationRules {
+    /// Keys of the attributes whose values are concatenated, e.g. `["Reference Designator"]`.
+    #[serde(default)]
+    pub concatenated_keys: Vec<String>,
+
+    /// Separator between concatenated values, ", " if not set.
+    pub separator: Option<String>,
+
+    /// Whether merged items with different values for any other attribute are rejected, rather
+    /// than keeping the first value and logging a warning.
+    #[serde(default)]
+    pub reject_conflicts: bool,
+}
+
+/// Rolls the values of a numeric attribute up the hierarchy. Each item gets an extended value,
+/// which is its value per one times its quantity. The value per one of a part is its own value,
+/// and that of an assembly is the sum of the extended values of its children.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RollupRules {
+    /// Key of the numeric attribute, e.g. "Unit Cost".
+    pub key: String,
+
+    /// Key of the attribute the extended values are written to, "Extended <key>" if not set.
+    pub output_key: Option<String>,
+
+    /// Key of the quantity to multiply by, the ItemSync `quantity_key` if not set.
+    pub quantity_key: Option<String>,
+
+    /// Whether an assembly's own value, if it has one, is used instead of the sum of its children,
+    /// e.g. for purchased assemblies with a known price.
+    #[serde(default)]
+    pub own_value_overrides: bool,
+}
+
+impl RollupRules {
+    /// Rolls up the attribute with the default output and quantity keys.
+    pub fn new(key: &str) -> Self {
+        RollupRules {
+            key: key.to_string(),
+            output_key: None,
+            quantity_key: None,
+            own_value_overrides: false,
+        }
+    }
+
+    /// Key of the attribute the extended values are written to.
+    pub fn output_key(&self) -> String {
+        self.output_key.clone().unwrap_or_else(|| format!("Extended {}", self.key))
+    }
+}
+
+/// How the numbers of a key are written. Numbers are read by dropping the suffix and the thousands
+/// separators, and then replacing the decimal separator with ".", so that e.g. "1.000,5 pcs" is
+/// read as 1000.5 with `","` as the decimal separator, `"."` as the thousands separator and
+/// `["pcs"]` as the suffixes.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NumberFormat {
+    /// Separator between the whole and fractional part of the number, "." if not set.
+    #[serde(default = "NumberFormat::default_decimal_separator")]
+    pub decimal_separator: char,
+
+    /// Separator between groups of digits, e.g. "," in "1,000" or " " in "1 000", where any
+    /// whitespace matches " ". Numbers with separators are rejected if not set.
+    pub thousands_separator: Option<char>,
+
+    /// Units or signs allowed after the number, e.g. `["pcs", "%"]`, which are matched ignoring
+    /// case and dropped, so that "4 PCS" is read as 4 and "15%" as 15.
+    #[serde(default)]
+    pub suffixes: Vec<String>,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            decimal_separator: Self::default_decimal_separator(),
+            thousands_separator: None,
+            suffixes: Vec::new(),
+        }
+    }
+}
+
+impl NumberFormat {
+    fn default_decimal_separator() -> char {
+        '.'
+    }
+
+    /// Rewrites the number in the standard format that `str::parse` reads, e.g. "1.000,5" to
+    /// "1000.5". Returns a description of the problem if the text has a "." or "," that isn't one
+    /// of the separators, or a thousands separator that doesn't separate groups of 3 digits,
+    /// following a first group of at most 3, before the decimal separator. That way e.g. "1.5" or
+    /// "1234.567" isn't silently read as 15 or 1234567.
+    pub fn normalize(&self, text: &str) -> std::result::Result<String, String> {
+        let mut number = text.trim();
+        for suffix in self.suffixes.iter().map(|suffix| suffix.trim()) {
+            let start = number.len().saturating_sub(suffix.len());
+            if number.is_char_boundary(start) && number[start..].eq_ignore_ascii_case(suffix) {
+                number = number[..start].trim_end();
+                break;
+            }
+        }
+        let is_thousands_separator = |c: char| match self.thousands_separator {
+            Some(separator) if separator.is_whitespace() => c.is_whitespace(),
+            Some(separator) => c == separator,
+            None => false,
+        };
+        let misplaced = || format!("{text:?} has a misplaced thousands separator");
+        let mut normalized = String::with_capacity(number.len());
+        let mut has_decimal_separator = false;
+        // Digits since the last thousands separator, if there was one, and before the first.
+        let mut group = None;
+        let mut leading_digits = 0;
+        for c in number.chars() {
+            if c == self.decimal_separator {
+                if matches!(group, Some(digits) if digits != 3) {
+                    return Err(misplaced());
+                }
+                has_decimal_separator = true;
+                group = None;
+                normalized.push('.');
+            } else if is_thousands_separator(c) {
+                if has_decimal_separator
+                    || matches!(group, Some(digits) if digits != 3)
+                    || (group.is_none() && leading_digits > 3)
+                    || !normalized.ends_with(|c: char| c.is_ascii_digit())
+                {
+                    return Err(misplaced());
+                }
+                group = Some(0);
+            } else if matches!(c, '.' | ',') {
+                return Err(format!(
+                    "{text:?} has an unexpected {c:?}, expected {:?} as the decimal separator",
+                    self.decimal_separator
+                ));
+            } else {
+                if c.is_ascii_digit() {
+                    match group.as_mut() {
+                        Some(digits) => *digits += 1,
+                        None => leading_digits += 1,
+                    }
+                }
+                normalized.push(c);
+            }
+        }
+        if matches!(group, Some(digits) if digits != 3) {
+            return Err(misplaced());
+        }
+        Ok(normalized)
+    }
+}
+
+#[derive(Debug, Deserialize)]
 pub enum OutputRules {
     ItemSync(ItemSyncFormatRules),
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ItemSyncFormatRules {
     pub id_key: String,
 
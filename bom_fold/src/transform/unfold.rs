@@ -0,0 +1,147 @@
+use crate::transform::{
+    data::{FlatData, FoldedData, Node, Value},
+    keys::position_of_key,
+};
+use std::borrow::Cow;
+
+/// How [`unfold`] writes the level of each item.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum LevelStyle {
+    /// The depth of the item as a number, where top level items have level 1. Can be folded again
+    /// with `ChildIdentificationPolicy::OrderedLevelKey`.
+    #[default]
+    Depth,
+
+    /// A dotted outline number like "1.2.10", numbering each item among its siblings. Can be folded
+    /// again with `ChildIdentificationPolicy::OutlineLevelKey`.
+    Outline,
+}
+
+/// Converts the `FoldedData` back into `FlatData`, the inverse of [`transform`](super::transform).
+///
+/// The items are listed depth first, with their level written to the `level_key` column. If the
+/// attribute keys already contain the level key, e.g. because the hierarchy was folded from it, its
+/// values are replaced. Otherwise the level is added as the first column.
+pub fn unfold<'a>(
+    folded_data: &FoldedData<'a>,
+    level_key: &str,
+    style: LevelStyle,
+) -> FlatData<'a> {
+    let mut keys = folded_data.attribute_keys.to_vec();
+    let level_index = position_of_key(&keys, level_key);
+    if level_index.is_none() {
+        keys.insert(0, Cow::from(level_key.to_string()));
+    }
+    let mut unfolder = Unfolder { level_index, style, records: Vec::new() };
+    unfolder.unfold_nodes(&folded_data.top_level_nodes, &mut Vec::new());
+    FlatData::new(keys, unfolder.records)
+}
+
+/// Accumulates the records while walking the hierarchy.
+struct Unfolder<'a> {
+    /// Position of the level among the attributes, or `None` if it's added as the first column.
+    level_index: Option<usize>,
+    style: LevelStyle,
+    records: Vec<Vec<Value<'a>>>,
+}
+
+impl<'a> Unfolder<'a> {
+    /// Adds the records of the nodes and their descendents. `outline` holds the 1-based position of
+    /// each ancestor among its siblings.
+    fn unfold_nodes(&mut self, nodes: &[Node<'a>], outline: &mut Vec<usize>) {
+        for (index, node) in nodes.iter().enumerate() {
+            outline.push(index + 1);
+            let level = match self.style {
+                LevelStyle::Depth => Value::Number(outline.len() as f64),
+                LevelStyle::Outline => Value::text_owned(
+                    outline.iter().map(ToString::to_string).collect::<Vec<_>>().join("."),
+                ),
+            };
+            let mut record = node.attributes.to_vec();
+            match self.level_index {
+                Some(index) => record[index] = level,
+                None => record.insert(0, level),
+            }
+            self.records.push(record);
+            self.unfold_nodes(&node.children, outline);
+            outline.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unfold, LevelStyle};
+    use crate::transform::{
+        data::{FlatData, FoldedData, Node, Value},
+        ordered_level_key,
+        rules::LevelValidation,
+    };
+    use pretty_assertions::assert_eq;
+    use std::borrow::Cow;
+
+    fn input() -> FlatData<'static> {
+        FlatData {
+            keys: vec![Cow::from("Part Number")],
+            records: ["A-100", "2001", "B-300", "N-400", "C-500"]
+                .into_iter()
+                .map(|number| vec![Value::text(number)])
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn folded<'a>(input: &'a FlatData<'a>) -> FoldedData<'a> {
+        let leaf = |index: usize| Node { attributes: &input.records[index], children: Vec::new() };
+        FoldedData {
+            attribute_keys: &input.keys,
+            top_level_nodes: vec![
+                Node {
+                    attributes: &input.records[0],
+                    children: vec![
+                        Node { attributes: &input.records[1], children: vec![leaf(2)] },
+                        leaf(3),
+                    ],
+                },
+                leaf(4),
+            ],
+        }
+    }
+
+    fn column(flat_data: &FlatData, index: usize) -> Vec<String> {
+        flat_data.records.iter().map(|record| record[index].to_string()).collect()
+    }
+
+    #[test]
+    fn adds_depth_level() {
+        let input = input();
+        let unfolded = unfold(&folded(&input), "Level", LevelStyle::Depth);
+        assert_eq!(unfolded.keys, ["Level", "Part Number"]);
+        assert_eq!(column(&unfolded, 0), ["1", "2", "3", "2", "1"]);
+        assert_eq!(column(&unfolded, 1), ["A-100", "2001", "B-300", "N-400", "C-500"]);
+
+        let refolded =
+            ordered_level_key::fold(&unfolded, "Level", LevelValidation::Strict).unwrap();
+        assert_eq!(refolded.top_level_nodes.len(), 2);
+        assert_eq!(refolded.top_level_nodes[0].children[0].children.len(), 1);
+    }
+
+    #[test]
+    fn replaces_outline_level() {
+        let mut input = input();
+        input.keys.push(Cow::from("level"));
+        for record in input.records.iter_mut() {
+            record.push(Value::Number(0.0));
+        }
+        let unfolded = unfold(&folded(&input), "level", LevelStyle::Outline);
+        assert_eq!(unfolded.keys, ["Part Number", "level"]);
+        assert_eq!(column(&unfolded, 1), ["1", "1.1", "1.1.1", "1.2", "2"]);
+
+        let refolded =
+            ordered_level_key::fold_outline(&unfolded, "level", LevelValidation::Strict).unwrap();
+        assert_eq!(
+            column(&unfold(&refolded, "level", LevelStyle::Outline), 1),
+            column(&unfolded, 1)
+        );
+    }
+}
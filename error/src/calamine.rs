@@ -0,0 +1,18 @@
+use crate::Error;
+
+impl From<calamine::XlsxError> for Error {
+    fn from(xlsx_error: calamine::XlsxError) -> Error {
+        match xlsx_error {
+            calamine::XlsxError::Io(_) => {
+                Error::internal("I/O error occurred while reading XLSX data.")
+            }
+            calamine::XlsxError::Password => {
+                Error::invalid_argument("Workbook is password protected")
+            }
+            calamine::XlsxError::WorksheetNotFound(name) => {
+                Error::not_found(format!("Worksheet {name:?} not found in workbook"))
+            }
+            e => Error::invalid_argument(format!("Could not read XLSX workbook: {e}")),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::error::Error;
 use core::num::{ParseIntError, TryFromIntError};
-use std::time::SystemTimeError;
+use std::{io, time::SystemTimeError};
 use time::OutOfRangeError;
 
 impl From<TryFromIntError> for Error {
@@ -26,3 +26,13 @@ impl From<SystemTimeError> for Error {
         Error::invalid_argument("SystemTime represents a point before the reference time")
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Error::not_found(format!("File not found: {e}")),
+            io::ErrorKind::PermissionDenied => Error::permission_denied(e.to_string()),
+            _ => Error::internal(format!("I/O error: {e}")),
+        }
+    }
+}
@@ -175,6 +175,43 @@ impl Error {
         }
     }
 
+    /// Numeric gRPC status code of `InvalidArgument` errors, for reporting invalid arguments
+    /// without constructing an error.
+    pub const INVALID_ARGUMENT_CODE: i32 = 3;
+
+    /// Numeric gRPC status code, e.g. 3 for `InvalidArgument`. Every variant has a distinct code.
+    pub const fn code(&self) -> i32 {
+        match self {
+            #[allow(deprecated)]
+            Error::Ok => 0,
+            Error::Cancelled(_) => 1,
+            Error::Unknown(_) => 2,
+            Error::InvalidArgument(_) => Self::INVALID_ARGUMENT_CODE,
+            Error::DeadlineExceeded(_) => 4,
+            Error::NotFound(_) => 5,
+            Error::AlreadyExists(_) => 6,
+            Error::PermissionDenied(_) => 7,
+            Error::ResourceExhausted(_) => 8,
+            Error::FailedPrecondition(_) => 9,
+            Error::Aborted(_) => 10,
+            Error::OutOfRange(_) => 11,
+            Error::Unimplemented(_) => 12,
+            Error::Internal(_) => 13,
+            Error::Unavailable(_) => 14,
+            Error::DataLoss(_) => 15,
+            Error::Unauthenticated(_) => 16,
+        }
+    }
+
+    /// Process exit code, the gRPC status code except for `Ok`, which exits with 1 rather than
+    /// signalling success.
+    pub const fn exit_code(&self) -> i32 {
+        match self.code() {
+            0 => 1,
+            code => code,
+        }
+    }
+
     pub fn message(&self) -> &str {
         match self {
             #[allow(deprecated)]
@@ -459,3 +496,10 @@ fn test_macro() {
     assert_eq!(e.message(), "Hello bar! 123");
     assert!(matches!(e, Error::Internal(_)));
 }
+
+#[test]
+#[allow(deprecated)]
+fn test_exit_code() {
+    assert_eq!(Error::Ok.exit_code(), 1);
+    assert_eq!(Error::invalid_argument("foo").exit_code(), Error::INVALID_ARGUMENT_CODE);
+}
@@ -8,6 +8,12 @@ pub use macros::*;
 #[cfg(feature = "csv")]
 pub mod csv;
 
+#[cfg(feature = "calamine")]
+pub mod calamine;
+
+#[cfg(feature = "xlsxwriter")]
+pub mod xlsxwriter;
+
 pub mod common;
 
 pub mod error_details;
@@ -0,0 +1,7 @@
+use crate::Error;
+
+impl From<xlsxwriter::XlsxError> for Error {
+    fn from(xlsx_error: xlsxwriter::XlsxError) -> Error {
+        Error::internal(format!("Could not write XLSX workbook: {xlsx_error}"))
+    }
+}